@@ -0,0 +1,387 @@
+mod sse;
+
+use std::{
+    collections::VecDeque,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{Stream, StreamExt};
+use protocol::{
+    CommandOutput, RunCommand,
+    api::{
+        ApiError, CreateVm, ExecEvent, Health, JobCreated, JobStatus, PoolStats, RunRequest,
+        RunResult, VmHealth, VmInfo, WorkspaceRequest,
+    },
+};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::io::AsyncWriteExt;
+
+use crate::sse::SseDecoder;
+
+/// Bytes asked for per request when fetching a file, below the guest's cap
+/// on a single read
+const FETCH_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Size of the whole file, sent along with a possibly partial read
+const FILE_SIZE_HEADER: &str = "x-file-size";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Sending the request or reading the response failed
+    Http(reqwest::Error),
+    /// The orchestrator answered with an error status
+    Api { status: u16, error: ApiError },
+    /// A response body did not have the expected shape
+    Decode(serde_json::Error),
+    /// Writing a fetched file failed
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "HTTP error: {}", e),
+            Error::Api { status, error } => write!(f, "API error ({}): {}", status, error),
+            Error::Decode(e) => write!(f, "Invalid response body: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            Error::Api { error, .. } => Some(error),
+            Error::Decode(e) => Some(e),
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Client for the orchestrator's HTTP API
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl Client {
+    /// `base_url` is where the orchestrator listens, e.g.
+    /// `http://localhost:3000`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    /// Like `new`, with a preconfigured `reqwest` client, e.g. for timeouts
+    pub fn with_http_client(base_url: impl Into<String>, http: reqwest::Client) -> Self {
+        Client {
+            http,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Boots a new VM. It accepts requests once its health is `Healthy`.
+    pub async fn create_vm(&self, options: &CreateVm) -> Result<VmInfo, Error> {
+        let response = self
+            .http
+            .post(self.url("/vms"))
+            .json(options)
+            .send()
+            .await?;
+
+        json(response).await
+    }
+
+    pub async fn list_vms(&self) -> Result<Vec<VmInfo>, Error> {
+        json(self.http.get(self.url("/vms")).send().await?).await
+    }
+
+    pub async fn get_vm(&self, id: &str) -> Result<VmInfo, Error> {
+        json(self.http.get(self.vm_url(id, "")).send().await?).await
+    }
+
+    /// Health of a VM as last seen by the orchestrator's heartbeat
+    pub async fn health(&self, id: &str) -> Result<Health, Error> {
+        let body: VmHealth = json(self.http.get(self.vm_url(id, "/health")).send().await?).await?;
+
+        Ok(body.health)
+    }
+
+    /// Stops the VM and removes everything it left on the host
+    pub async fn destroy_vm(&self, id: &str) -> Result<(), Error> {
+        check(self.http.delete(self.vm_url(id, "")).send().await?).await?;
+
+        Ok(())
+    }
+
+    /// Idle VMs and hit counts of each pooled template
+    pub async fn pool_stats(&self) -> Result<PoolStats, Error> {
+        json(self.http.get(self.url("/pool")).send().await?).await
+    }
+
+    /// Runs a command and waits for it to finish
+    pub async fn exec(&self, id: &str, cmd: &RunCommand) -> Result<CommandOutput, Error> {
+        let response = self
+            .http
+            .post(self.vm_url(id, "/exec"))
+            .json(cmd)
+            .send()
+            .await?;
+
+        json(response).await
+    }
+
+    /// Starts a command and returns the ID of the job to poll for its result
+    pub async fn exec_detached(&self, id: &str, cmd: &RunCommand) -> Result<String, Error> {
+        let response = self
+            .http
+            .post(self.vm_url(id, "/exec"))
+            .query(&[("detach", true)])
+            .json(cmd)
+            .send()
+            .await?;
+
+        Ok(json::<JobCreated>(response).await?.job_id)
+    }
+
+    /// Runs a command and yields its output as it arrives, ending with an
+    /// `Exit` or `Error` event
+    pub async fn exec_stream(&self, id: &str, cmd: &RunCommand) -> Result<ExecStream, Error> {
+        let response = self
+            .http
+            .post(self.vm_url(id, "/exec/stream"))
+            .json(cmd)
+            .send()
+            .await?;
+
+        Ok(ExecStream::new(check(response).await?))
+    }
+
+    /// Unpacks `archive`, a tarball compressed as `options.compression`
+    /// says, into a fresh workspace and runs its entrypoint. Artifacts end
+    /// up in a guest tarball named by `CommandOutput::artifacts`.
+    pub async fn run_workspace(
+        &self,
+        id: &str,
+        archive: Vec<u8>,
+        options: &WorkspaceRequest,
+    ) -> Result<CommandOutput, Error> {
+        let response = self
+            .http
+            .post(self.vm_url(id, "/workspace"))
+            .multipart(workspace_form(archive, options)?)
+            .send()
+            .await?;
+
+        json(response).await
+    }
+
+    /// Like `run_workspace`, returning the ID of the job to poll instead
+    pub async fn run_workspace_detached(
+        &self,
+        id: &str,
+        archive: Vec<u8>,
+        options: &WorkspaceRequest,
+    ) -> Result<String, Error> {
+        let response = self
+            .http
+            .post(self.vm_url(id, "/workspace"))
+            .query(&[("detach", true)])
+            .multipart(workspace_form(archive, options)?)
+            .send()
+            .await?;
+
+        Ok(json::<JobCreated>(response).await?.job_id)
+    }
+
+    /// Boots a VM just for `archive`, runs it and destroys the VM again.
+    /// The artifacts tarball comes back in the reply.
+    pub async fn run(&self, archive: Vec<u8>, options: &RunRequest) -> Result<RunResult, Error> {
+        let response = self
+            .http
+            .post(self.url("/run"))
+            .multipart(workspace_form(archive, options)?)
+            .send()
+            .await?;
+
+        json(response).await
+    }
+
+    pub async fn job_status(&self, id: &str, job_id: &str) -> Result<JobStatus, Error> {
+        let url = self.vm_url(id, &format!("/jobs/{}", job_id));
+
+        json(self.http.get(url).send().await?).await
+    }
+
+    /// Asks the guest to stop a running job. Its status reports the
+    /// outcome.
+    pub async fn cancel_job(&self, id: &str, job_id: &str) -> Result<(), Error> {
+        let url = self.vm_url(id, &format!("/jobs/{}", job_id));
+
+        check(self.http.delete(url).send().await?).await?;
+
+        Ok(())
+    }
+
+    /// Creates or replaces a file on the guest
+    pub async fn write_file(&self, id: &str, path: &str, data: Vec<u8>) -> Result<(), Error> {
+        let response = self
+            .http
+            .put(self.vm_url(id, "/fs/file"))
+            .query(&[("path", path)])
+            .body(data)
+            .send()
+            .await?;
+
+        check(response).await?;
+
+        Ok(())
+    }
+
+    /// Copies a guest file, such as an artifacts tarball, to `local`.
+    /// Returns its size.
+    pub async fn fetch_file(&self, id: &str, remote: &str, local: &Path) -> Result<u64, Error> {
+        let mut file = tokio::fs::File::create(local).await?;
+        let mut offset = 0;
+
+        loop {
+            let response = self
+                .http
+                .get(self.vm_url(id, "/fs/file"))
+                .query(&[("path", remote)])
+                .query(&[("offset", offset), ("length", FETCH_CHUNK_SIZE)])
+                .send()
+                .await?;
+
+            let response = check(response).await?;
+
+            let size = response
+                .headers()
+                .get(FILE_SIZE_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+
+            let data = response.bytes().await?;
+
+            file.write_all(&data).await?;
+            offset += data.len() as u64;
+
+            // An empty read means the file shrank while we were reading it
+            if data.is_empty() || size.is_none_or(|size| offset >= size) {
+                break;
+            }
+        }
+
+        file.flush().await?;
+
+        Ok(offset)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    fn vm_url(&self, id: &str, path: &str) -> String {
+        self.url(&format!("/vms/{}{}", id, path))
+    }
+}
+
+fn workspace_form(
+    archive: Vec<u8>,
+    options: &impl Serialize,
+) -> Result<reqwest::multipart::Form, Error> {
+    let archive = reqwest::multipart::Part::bytes(archive).file_name("workspace.tar");
+
+    Ok(reqwest::multipart::Form::new()
+        .text("options", serde_json::to_string(options)?)
+        .part("archive", archive))
+}
+
+/// Turns an error status into `Error::Api`, with the `ApiError` the
+/// orchestrator sent or the plain body as its message
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = response.status();
+
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await?;
+    let error = serde_json::from_str(&body).unwrap_or(ApiError {
+        kind: None,
+        message: body,
+    });
+
+    Err(Error::Api {
+        status: status.as_u16(),
+        error,
+    })
+}
+
+async fn json<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, Error> {
+    let body = check(response).await?.bytes().await?;
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+type ByteStream = Pin<Box<dyn Stream<Item = reqwest::Result<bytes::Bytes>> + Send>>;
+
+/// Events of a streamed exec, read from the orchestrator's SSE response
+pub struct ExecStream {
+    body: ByteStream,
+    decoder: SseDecoder,
+    events: VecDeque<String>,
+}
+
+impl ExecStream {
+    fn new(response: reqwest::Response) -> Self {
+        ExecStream {
+            body: Box::pin(response.bytes_stream()),
+            decoder: SseDecoder::default(),
+            events: VecDeque::new(),
+        }
+    }
+}
+
+impl Stream for ExecStream {
+    type Item = Result<ExecEvent, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(data) = self.events.pop_front() {
+                return Poll::Ready(Some(serde_json::from_str(&data).map_err(Error::from)));
+            }
+
+            match self.body.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(bytes))) => {
+                    let events = self.decoder.push(&bytes);
+                    self.events.extend(events);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
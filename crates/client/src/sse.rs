@@ -0,0 +1,50 @@
+/// Splits a `text/event-stream` body into the data of its events. Only `\n`
+/// line endings are understood, which is what the orchestrator sends.
+#[derive(Default)]
+pub(crate) struct SseDecoder {
+    buf: Vec<u8>,
+}
+
+impl SseDecoder {
+    /// Adds received bytes and returns the data of every event they complete
+    pub(crate) fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(bytes);
+
+        let mut events = Vec::new();
+
+        while let Some(end) = self.buf.windows(2).position(|w| w == b"\n\n") {
+            let raw: Vec<u8> = self.buf.drain(..end + 2).collect();
+            let raw = String::from_utf8_lossy(&raw[..end]);
+
+            // Comments, event names and ids carry nothing the client needs
+            let data: Vec<&str> = raw
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                .collect();
+
+            if !data.is_empty() {
+                events.push(data.join("\n"));
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_split_across_chunks() {
+        let mut decoder = SseDecoder::default();
+
+        assert!(decoder.push(b": keep-alive\n\ndata: {\"a\"").is_empty());
+        assert_eq!(
+            decoder.push(b":1}\n\nevent: x\ndata:2\n"),
+            vec!["{\"a\":1}"]
+        );
+        assert_eq!(decoder.push(b"data: 3\n\n"), vec!["2\n3"]);
+    }
+}
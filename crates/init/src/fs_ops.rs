@@ -0,0 +1,155 @@
+use std::{io::SeekFrom, os::unix::fs::PermissionsExt, path::Path, time::UNIX_EPOCH};
+
+use protocol::{
+    Message,
+    fs::{DirEntry, FileKind, FileStat, FsError, FsRequest},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::info;
+
+use crate::messaging::{self, Outbox};
+
+/// Upper bound of a single `ReadFile`, well below the frame size limit
+const MAX_READ_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Runs a filesystem request and replies with its result, or with an
+/// `FsError` describing why it failed
+pub async fn handle(
+    outbox: &Outbox,
+    request_id: u64,
+    request: FsRequest,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let message = match run(request).await {
+        Ok(m) => m,
+        Err(e) => {
+            info!("Filesystem request {} failed: {}", request_id, e);
+            Message::FsError(FsError::from(e))
+        }
+    };
+
+    messaging::reply(outbox, request_id, message).await
+}
+
+async fn run(request: FsRequest) -> std::io::Result<Message> {
+    match request {
+        FsRequest::ListDir { path } => {
+            let mut entries = Vec::new();
+            let mut dir = tokio::fs::read_dir(&path).await?;
+
+            while let Some(entry) = dir.next_entry().await? {
+                // An entry removed since `read_dir` saw it is left out
+                // rather than failing the whole listing
+                let stat = match stat(&entry.path()).await {
+                    Ok(stat) => stat,
+                    Err(e) => {
+                        info!("Skipping {:?} in listing: {}", entry.path(), e);
+                        continue;
+                    }
+                };
+
+                entries.push(DirEntry {
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    stat,
+                });
+            }
+
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+            Ok(Message::DirListing(entries))
+        }
+        FsRequest::Stat { path } => Ok(Message::FileStat(stat(Path::new(&path)).await?)),
+        FsRequest::ReadFile {
+            path,
+            offset,
+            length,
+        } => {
+            let mut file = tokio::fs::File::open(&path).await?;
+            let size = file.metadata().await?.len();
+
+            file.seek(SeekFrom::Start(offset)).await?;
+
+            let mut data = Vec::new();
+            file.take(length.min(MAX_READ_BYTES))
+                .read_to_end(&mut data)
+                .await?;
+
+            Ok(Message::FileData { data, size })
+        }
+        FsRequest::WriteFile {
+            path,
+            data,
+            append,
+            mode,
+        } => {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(append)
+                .truncate(!append)
+                .open(&path)
+                .await?;
+
+            file.write_all(&data).await?;
+            file.flush().await?;
+
+            if let Some(mode) = mode {
+                file.set_permissions(std::fs::Permissions::from_mode(mode))
+                    .await?;
+            }
+
+            Ok(Message::FsDone)
+        }
+        FsRequest::CreateDir { path, recursive } => {
+            match recursive {
+                true => tokio::fs::create_dir_all(&path).await?,
+                false => tokio::fs::create_dir(&path).await?,
+            }
+
+            Ok(Message::FsDone)
+        }
+        FsRequest::Remove { path, recursive } => {
+            let meta = tokio::fs::symlink_metadata(&path).await?;
+
+            match (meta.is_dir(), recursive) {
+                (true, true) => tokio::fs::remove_dir_all(&path).await?,
+                (true, false) => tokio::fs::remove_dir(&path).await?,
+                (false, _) => tokio::fs::remove_file(&path).await?,
+            }
+
+            Ok(Message::FsDone)
+        }
+        FsRequest::Rename { from, to } => {
+            tokio::fs::rename(&from, &to).await?;
+
+            Ok(Message::FsDone)
+        }
+    }
+}
+
+async fn stat(path: &Path) -> std::io::Result<FileStat> {
+    let meta = tokio::fs::symlink_metadata(path).await?;
+    let file_type = meta.file_type();
+
+    let kind = if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_dir() {
+        FileKind::Dir
+    } else if file_type.is_file() {
+        FileKind::File
+    } else {
+        FileKind::Other
+    };
+
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+
+    Ok(FileStat {
+        kind,
+        size: meta.len(),
+        mode: meta.permissions().mode() & 0o7777,
+        modified,
+    })
+}
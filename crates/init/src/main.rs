@@ -2,10 +2,17 @@ use std::{collections::HashMap, io::Write, process::Command};
 
 use nix::sys::reboot::{RebootMode, reboot};
 use tokio_vsock::{VsockAddr, VsockListener, VsockStream};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
+mod fs_ops;
 mod messaging;
 mod mounts;
+mod process;
+mod pty;
+mod transfer;
+
+/// Number of runs executing at once unless `vm.max_concurrency` is given
+const DEFAULT_MAX_CONCURRENCY: usize = 8;
 
 fn shutdown_actions() {
     // Flush all file system buffers to ensure data integrity before rebooting
@@ -124,7 +131,7 @@ async fn main() {
 
     info!("Init started. Listening on cid 3, port 5001...");
 
-    let (mut stream, addr) = match listener.accept().await {
+    let (stream, addr) = match listener.accept().await {
         Ok((s, a)) => (s, a),
         Err(e) => {
             info!("Failed to accept vsock connection: {}", e);
@@ -135,7 +142,38 @@ async fn main() {
 
     info!("Connection accepted from {:?}", addr);
 
-    messaging::handle_messages(&mut stream).await;
+    let max_concurrency = match params
+        .get("vm.max_concurrency")
+        .and_then(|v| v.parse().ok())
+    {
+        // No request would ever get a slot
+        Some(0) => {
+            warn!(
+                "Ignoring vm.max_concurrency=0, running at most {} requests at once",
+                DEFAULT_MAX_CONCURRENCY
+            );
+            DEFAULT_MAX_CONCURRENCY
+        }
+        Some(n) => n,
+        None => DEFAULT_MAX_CONCURRENCY,
+    };
+
+    let max_frame_size = params
+        .get("vm.max_frame_size")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(protocol::DEFAULT_MAX_FRAME_SIZE);
+
+    info!(
+        "Running at most {} requests at once, frames up to {} bytes",
+        max_concurrency, max_frame_size
+    );
+
+    let limits = messaging::Limits {
+        max_concurrency,
+        max_frame_size,
+    };
+
+    let stream = messaging::handle_messages(stream, limits).await;
 
     close_stream(stream);
     shutdown_actions();
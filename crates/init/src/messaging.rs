@@ -1,102 +1,484 @@
-use std::{os::unix::fs::PermissionsExt, path::Path, process::Output};
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use nix::sys::signal::Signal;
+use protocol::{
+    Capability, ErrorKind, Hello, Message, ProtocolError, PtyOpen, RequestError, RunCommand,
+    UploadBegin, WorkspaceRunOptions, WorkspaceSource, tar::Compression,
+};
+use tokio::{
+    process::Child,
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc},
+    task::JoinSet,
+};
+use tokio_vsock::{OwnedWriteHalf, VsockStream};
+use tracing::{error, info, warn};
+
+use crate::{
+    fs_ops,
+    process::{self, Control, Controls, ExecOptions},
+    pty,
+    transfer::{self, FetchTarget},
+};
+
+/// Replies queued for the writer task, tagged with the request they answer
+pub type Outbox = mpsc::Sender<(u64, Message)>;
+
+const OUTBOX_CAPACITY: usize = 64;
+
+/// Announced to the host in `Hello`
+const CAPABILITIES: &[Capability] = &[
+    Capability::Streaming,
+    Capability::Pty,
+    Capability::FileOps,
+    Capability::FileTransfer,
+    Capability::Compression,
+    Capability::Heartbeat,
+];
+
+/// Workspace runs unpack into `<prefix>-<request ID>`
+const WORKSPACE_PREFIX: &str = "/tmp/workspace";
+
+/// Where workspace runs leave their artifact tarballs for the host to fetch
+const ARTIFACTS_DIR: &str = "/tmp/artifacts";
+
+pub struct Limits {
+    /// Runs executing at once, further runs wait for a free slot
+    pub max_concurrency: usize,
+    /// Largest frame body accepted from or sent to the host
+    pub max_frame_size: usize,
+}
+
+enum Job {
+    Command(RunCommand),
+    Workspace(WorkspaceRunOptions),
+    Pty(PtyOpen),
+    Upload(UploadBegin),
+    Download(FetchTarget),
+    Fs(protocol::fs::FsRequest),
+}
 
-use protocol::{RunCommand, WorkspaceRunOptions};
-use tokio_vsock::VsockStream;
-use tracing::{error, info};
+/// Reads requests from the host until it asks for a shutdown or disconnects.
+///
+/// Every run is spawned as its own task, at most `limits.max_concurrency` of them
+/// executing at once; the rest wait for a free slot. Interactive pty
+/// sessions do not take a slot. Control messages are handled as soon as
+/// they arrive. All replies go through a single writer
+/// task.
+pub async fn handle_messages(stream: VsockStream, limits: Limits) -> VsockStream {
+    let (mut reader, writer) = stream.into_split();
+
+    let (outbox, replies) = mpsc::channel(OUTBOX_CAPACITY);
+
+    // Plain JSON until the host's `Hello` tells which versions it speaks
+    let version = Arc::new(AtomicU32::new(protocol::MIN_VERSION));
+    let writer_task = tokio::spawn(write_replies(
+        writer,
+        replies,
+        limits.max_frame_size,
+        version.clone(),
+    ));
+
+    let controls = Controls::default();
+    let slots = Arc::new(Semaphore::new(limits.max_concurrency));
+    let mut jobs = JoinSet::new();
 
-pub async fn handle_messages(stream: &mut VsockStream) {
     loop {
-        let message = match protocol::recv_msg(stream).await {
-            Ok(m) => m,
-            Err(e) => return error!("Error reading from stream: {}", e),
+        // Reap finished jobs so the set does not grow for the whole session
+        while jobs.try_join_next().is_some() {}
+
+        let options = protocol::WireOptions {
+            max_frame_size: limits.max_frame_size,
+            max_version: version.load(Ordering::Relaxed),
+        };
+
+        let envelope = match protocol::recv_msg_with(&mut reader, &options).await {
+            Ok(e) => e,
+            Err(ProtocolError::Disconnected) => {
+                info!("Host disconnected");
+                break;
+            }
+            // The frame was read in full, so the connection is still usable
+            Err(e @ (ProtocolError::UnsupportedVersion { .. } | ProtocolError::Malformed(_))) => {
+                warn!("Skipping frame: {}", e);
+                continue;
+            }
+            Err(e) => {
+                error!("Closing connection: {}", e);
+                break;
+            }
         };
 
-        match message {
-            protocol::Message::Hello => {
-                info!("Orchestrator said Hello! Sending response...");
+        let request_id = envelope.request_id;
 
-                match protocol::send_msg(stream, protocol::Message::Hello).await {
-                    Ok(_) => continue,
-                    Err(e) => {
-                        error!("Error responding to hello message: {}", e);
-                        continue;
+        let job = match envelope.message {
+            Message::Hello(peer) => {
+                let hello = Hello::new(CAPABILITIES.to_vec());
+
+                match hello.negotiate(&peer) {
+                    Ok(v) => {
+                        info!(
+                            "Orchestrator said Hello with versions {}..={}, using {}",
+                            peer.min_version, peer.max_version, v
+                        );
+                        version.store(v, Ordering::Relaxed);
                     }
+                    // The host sees the same mismatch in our Hello and disconnects
+                    Err(e) => error!("No common protocol version with host: {}", e),
+                }
+
+                if let Err(e) = reply(&outbox, request_id, Message::Hello(hello)).await {
+                    error!("Error responding to hello message: {}", e);
+                }
+                continue;
+            }
+            // Answered from the reader loop, so a busy guest still counts as alive
+            Message::Ping { nonce } => {
+                if let Err(e) = reply(&outbox, request_id, Message::Pong { nonce }).await {
+                    error!("Error responding to ping: {}", e);
                 }
+                continue;
             }
-            protocol::Message::RunCommand(cmd) => {
+            Message::RunCommand(cmd) => {
                 info!("Received RunCommand: {}", cmd.command);
-                match handle_run_individual_command(stream, cmd).await {
-                    Ok(_) => continue,
-                    Err(e) => {
-                        error!("Error running command: {}", e);
-                        continue;
-                    }
+                Job::Command(cmd)
+            }
+            Message::RunWorkspace(wo) => Job::Workspace(wo),
+            Message::PtyOpen(open) => {
+                info!("Received PtyOpen: {}", open.command);
+                Job::Pty(open)
+            }
+            Message::UploadBegin(begin) => {
+                info!(
+                    "Received UploadBegin: {} ({} bytes)",
+                    begin.path, begin.size
+                );
+                Job::Upload(begin)
+            }
+            Message::UploadChunk {
+                request_id: target,
+                seq,
+                data,
+            } => {
+                if !controls.send(target, Control::Chunk { seq, data }) {
+                    warn!("Chunk for unknown upload {}", target);
+                }
+                continue;
+            }
+            Message::UploadEnd { request_id: target } => {
+                if !controls.send(target, Control::CloseInput) {
+                    warn!("End of unknown upload {}", target);
+                }
+                continue;
+            }
+            Message::Fs(request) => Job::Fs(request),
+            Message::FetchFile { path } => {
+                info!("Received FetchFile: {}", path);
+                Job::Download(FetchTarget::File(path))
+            }
+            Message::FetchDir { path } => {
+                info!("Received FetchDir: {}", path);
+                Job::Download(FetchTarget::Dir(path))
+            }
+            Message::PtyInput {
+                request_id: target,
+                data,
+            } => {
+                if !controls.send(target, Control::Input(data)) {
+                    warn!("Input for unknown pty session {}", target);
+                }
+                continue;
+            }
+            Message::StdinChunk {
+                request_id: target,
+                data,
+            } => {
+                if !controls.send(target, Control::Input(data)) {
+                    warn!("Stdin for unknown request {}", target);
+                }
+                continue;
+            }
+            Message::StdinClose { request_id: target } => {
+                if !controls.send(target, Control::CloseInput) {
+                    warn!("Stdin close for unknown request {}", target);
+                }
+                continue;
+            }
+            Message::PtyResize {
+                request_id: target,
+                size,
+            } => {
+                if !controls.send(target, Control::Resize(size)) {
+                    warn!("Resize for unknown pty session {}", target);
+                }
+                continue;
+            }
+            Message::Cancel { request_id: target } => {
+                if !controls.send(target, Control::Cancel) {
+                    warn!("Cancel for unknown request {}", target);
                 }
+                continue;
             }
-            protocol::Message::RunWorkspace(wo) => match handle_run_workspace(stream, wo).await {
-                Ok(_) => continue,
-                Err(e) => {
-                    error!("Error running workspace: {}", e);
-                    continue;
+            Message::Signal {
+                request_id: target,
+                signal,
+            } => {
+                match Signal::try_from(signal) {
+                    Ok(signal) => {
+                        if !controls.send(target, Control::Signal(signal)) {
+                            warn!("Signal for unknown request {}", target);
+                        }
+                    }
+                    Err(e) => warn!("Invalid signal {} for request {}: {}", signal, target, e),
                 }
-            },
-            protocol::Message::Shutdown => {
+                continue;
+            }
+            Message::Shutdown => {
                 info!("Shutting down guest...");
-                return;
+                break;
+            }
+            _ => {
+                info!("Received other message");
+                continue;
+            }
+        };
+
+        jobs.spawn(run_job(
+            request_id,
+            job,
+            controls.register(request_id),
+            outbox.clone(),
+            controls.clone(),
+            slots.clone(),
+        ));
+    }
+
+    // Aborting a job drops its child, which kills it
+    jobs.shutdown().await;
+
+    // The writer stops once every outbox sender is gone
+    drop(outbox);
+
+    let writer = writer_task.await.expect("Writer task panicked");
+
+    reader.unsplit(writer)
+}
+
+pub async fn reply(
+    outbox: &Outbox,
+    request_id: u64,
+    message: Message,
+) -> Result<(), Box<dyn std::error::Error>> {
+    outbox
+        .send((request_id, message))
+        .await
+        .map_err(|_| "connection to host closed".into())
+}
+
+async fn write_replies(
+    mut writer: OwnedWriteHalf,
+    mut replies: mpsc::Receiver<(u64, Message)>,
+    max_frame_size: usize,
+    version: Arc<AtomicU32>,
+) -> OwnedWriteHalf {
+    while let Some((request_id, message)) = replies.recv().await {
+        let options = protocol::WireOptions {
+            max_frame_size,
+            max_version: version.load(Ordering::Relaxed),
+        };
+
+        match protocol::send_msg_with(&mut writer, request_id, message, &options).await {
+            Ok(_) => (),
+            Err(e @ (ProtocolError::Disconnected | ProtocolError::Io(_))) => {
+                error!("Error sending reply to request {}: {}", request_id, e);
+            }
+            // Nothing was written, such as for a frame over the size limit.
+            // The host would otherwise wait for a reply that never comes.
+            Err(e) => {
+                error!("Error sending reply to request {}: {}", request_id, e);
+
+                let failure = Message::Error {
+                    request_id,
+                    kind: ErrorKind::Internal,
+                    message: format!("Failed to send reply: {}", e),
+                };
+
+                if let Err(e) =
+                    protocol::send_msg_with(&mut writer, request_id, failure, &options).await
+                {
+                    error!(
+                        "Error reporting failed reply to request {}: {}",
+                        request_id, e
+                    );
+                }
             }
-            _ => info!("Received other message"),
         }
     }
+
+    writer
+}
+
+async fn run_job(
+    request_id: u64,
+    job: Job,
+    job_controls: mpsc::UnboundedReceiver<Control>,
+    outbox: Outbox,
+    controls: Controls,
+    slots: Arc<Semaphore>,
+) {
+    let result = match acquire_slot(&job, slots).await {
+        Ok(_slot) => match job {
+            Job::Command(cmd) => {
+                handle_run_individual_command(&outbox, request_id, cmd, job_controls).await
+            }
+            Job::Workspace(wo) => handle_run_workspace(&outbox, request_id, wo, job_controls).await,
+            Job::Pty(open) => pty::run_session(&outbox, request_id, open, job_controls).await,
+            Job::Upload(begin) => {
+                transfer::receive_upload(&outbox, request_id, begin, job_controls).await
+            }
+            Job::Download(target) => {
+                transfer::send_download(&outbox, request_id, target, job_controls).await
+            }
+            Job::Fs(request) => fs_ops::handle(&outbox, request_id, request).await,
+        },
+        Err(e) => Err(e.into()),
+    };
+
+    // A failed job has not sent its final reply, the host is still waiting
+    let failure = result.err().map(|e| {
+        error!("Error running request {}: {}", request_id, e);
+
+        match e.downcast_ref::<RequestError>() {
+            Some(re) => (re.kind, re.message.clone()),
+            None => (ErrorKind::Internal, e.to_string()),
+        }
+    });
+
+    if let Some((kind, message)) = failure {
+        let message = Message::Error {
+            request_id,
+            kind,
+            message,
+        };
+
+        if let Err(e) = reply(&outbox, request_id, message).await {
+            error!("Error reporting failure of request {}: {}", request_id, e);
+        }
+    }
+
+    controls.unregister(request_id);
+}
+
+/// Waits for a free job slot, for at most the timeout of the job. Interactive
+/// sessions are long-lived, transfers and filesystem requests run no process,
+/// none of them counts against the cap.
+async fn acquire_slot(
+    job: &Job,
+    slots: Arc<Semaphore>,
+) -> Result<Option<OwnedSemaphorePermit>, RequestError> {
+    let timeout_ms = match job {
+        Job::Command(cmd) => cmd.timeout_ms,
+        Job::Workspace(wo) => wo.timeout_ms,
+        Job::Pty(_) | Job::Upload(_) | Job::Download(_) | Job::Fs(_) => return Ok(None),
+    };
+
+    let closed = |_| RequestError::new(ErrorKind::Internal, "Job slots closed");
+
+    match timeout_ms {
+        Some(ms) => tokio::time::timeout(Duration::from_millis(ms), slots.acquire_owned())
+            .await
+            .map_err(|_| {
+                RequestError::new(ErrorKind::Timeout, "Timed out waiting for a free job slot")
+            })?
+            .map(Some)
+            .map_err(closed),
+        None => slots.acquire_owned().await.map(Some).map_err(closed),
+    }
 }
 
 async fn handle_run_individual_command(
-    stream: &mut VsockStream,
+    outbox: &Outbox,
+    request_id: u64,
     cmd: RunCommand,
+    controls: mpsc::UnboundedReceiver<Control>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+
+    let stdin = match cmd.stdin.is_some() || cmd.keep_stdin_open {
+        true => std::process::Stdio::piped(),
+        false => std::process::Stdio::null(),
+    };
+
     let child = tokio::process::Command::new(&cmd.command)
         .args(&cmd.args)
         .envs(&cmd.env)
         .current_dir(cmd.working_dir.unwrap_or_else(|| "/".to_string()))
+        .stdin(stdin)
         .stdout(std::process::Stdio::piped())
         .stderr(std::process::Stdio::piped())
-        .spawn()?;
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| RequestError::new(ErrorKind::SpawnFailed, e.to_string()))?;
+
+    let options = ExecOptions {
+        streaming: cmd.stream_output,
+        timeout: cmd.timeout_ms.map(Duration::from_millis),
+        stdin: cmd.stdin,
+        keep_stdin_open: cmd.keep_stdin_open,
+    };
 
-    let out = child.wait_with_output().await?;
+    let message = process::supervise(outbox, request_id, child, options, controls, started).await?;
 
-    info!("Command exited with status: {}", out.status);
+    reply(outbox, request_id, message).await
+}
 
-    let stdout_str = String::from_utf8_lossy(&out.stdout).trim().to_string();
+async fn handle_run_workspace(
+    outbox: &Outbox,
+    request_id: u64,
+    wo: WorkspaceRunOptions,
+    controls: mpsc::UnboundedReceiver<Control>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // One directory per run, as runs execute concurrently
+    let workspace = format!("{}-{}", WORKSPACE_PREFIX, request_id);
 
-    info!("Command out: {:?}", stdout_str);
+    let result = run_workspace(outbox, request_id, wo, controls, &workspace).await;
 
-    protocol::send_msg(
-        stream,
-        protocol::Message::CommandOutput(protocol::CommandOutput {
-            output: stdout_str.to_string(),
-        }),
-    )
-    .await?;
+    if let Err(e) = std::fs::remove_dir_all(&workspace)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        warn!("Failed to remove workspace {}: {}", workspace, e);
+    }
 
-    Ok(())
+    result
 }
 
-async fn handle_run_workspace(
-    stream: &mut VsockStream,
+async fn run_workspace(
+    outbox: &Outbox,
+    request_id: u64,
     wo: WorkspaceRunOptions,
+    controls: mpsc::UnboundedReceiver<Control>,
+    workspace: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Received file transfer of {} bytes", wo.data.len());
-
-    let workspace = "/tmp/workspace";
-
-    match save_upload_payload(workspace, &wo.data) {
+    match prepare_workspace(workspace, &wo.source, wo.compression) {
         Ok(_) => info!("Workspace directory ready"),
         Err(e) => {
             error!("Error creating workspace: {}", e);
-            return Err(e);
+            return Err(RequestError::new(ErrorKind::ExtractFailed, e.to_string()).into());
         }
     }
 
-    let entrypoint = Path::join(Path::new(workspace), &wo.entrypoint);
+    let Some(entrypoint) = resolve_entrypoint(workspace, &wo.entrypoint) else {
+        let message = format!("{} not found in workspace", wo.entrypoint);
+        return Err(RequestError::new(ErrorKind::EntrypointNotFound, message).into());
+    };
 
     info!("Entry point defined: {:?}", entrypoint);
 
@@ -104,39 +486,47 @@ async fn handle_run_workspace(
         Ok(_) => info!("Entrypoint converted to executable"),
         Err(e) => {
             error!("Failed to make entrypoint executable: {}", e);
-            return Err(e);
+            return Err(RequestError::new(ErrorKind::SpawnFailed, e.to_string()).into());
         }
     }
 
-    let out = match run_program(&entrypoint, workspace).await {
-        Ok(o) => o,
+    let started = Instant::now();
+
+    let child = match run_program(&entrypoint, workspace) {
+        Ok(c) => c,
         Err(e) => {
             error!("Error running program: {}", e);
-            return Err(e);
+            return Err(RequestError::new(ErrorKind::SpawnFailed, e.to_string()).into());
         }
     };
 
-    match out.status.success() {
-        true => info!("Process completed successfully"),
-        false => {
-            error!("Process exited with status: {}", out.status);
-            let msg = format!("Process exited with status: {}", out.status).to_string();
-            return Err(msg.into());
-        }
-    }
+    let options = ExecOptions {
+        streaming: wo.stream_output,
+        timeout: wo.timeout_ms.map(Duration::from_millis),
+        stdin: None,
+        keep_stdin_open: false,
+    };
 
-    let stdout_str = String::from_utf8_lossy(&out.stdout).trim().to_string();
+    let mut message =
+        process::supervise(outbox, request_id, child, options, controls, started).await?;
 
-    info!("Command stdout: {}", stdout_str);
+    if !wo.artifacts.is_empty() {
+        let archive = match collect_artifacts(workspace, &wo.artifacts, request_id) {
+            Ok(a) => a,
+            Err(e) => {
+                error!("Error collecting artifacts: {}", e);
+                None
+            }
+        };
 
-    match protocol::send_msg(
-        stream,
-        protocol::Message::CommandOutput(protocol::CommandOutput {
-            output: stdout_str.to_string(),
-        }),
-    )
-    .await
-    {
+        match &mut message {
+            Message::CommandOutput(output) => output.artifacts = archive,
+            Message::ProcessExit(exit) => exit.artifacts = archive,
+            _ => (),
+        }
+    }
+
+    match reply(outbox, request_id, message).await {
         Ok(_) => info!("Command output sent"),
         Err(e) => {
             error!("Error sending command output: {}", e);
@@ -147,21 +537,100 @@ async fn handle_run_workspace(
     Ok(())
 }
 
-fn save_upload_payload(workspace: &str, data: &Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+/// Packs the workspace files matching any of `patterns` into a tarball and
+/// returns its path, or `None` if nothing matched
+fn collect_artifacts(
+    workspace: &str,
+    patterns: &[String],
+    request_id: u64,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+
+    for pattern in patterns {
+        let full_pattern = Path::new(workspace).join(pattern);
+
+        for entry in glob::glob(&full_pattern.to_string_lossy())? {
+            let path = entry?;
+
+            let relative = match path.strip_prefix(workspace) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+
+            // Patterns like `../*` must not reach outside the workspace
+            if relative
+                .components()
+                .any(|c| matches!(c, std::path::Component::ParentDir))
+            {
+                warn!("Skipping artifact outside the workspace: {:?}", path);
+                continue;
+            }
+
+            if path.is_file() {
+                files.push(relative.to_string_lossy().into_owned());
+            }
+        }
+    }
+
+    files.sort();
+    files.dedup();
+
+    if files.is_empty() {
+        info!("No artifacts matched {:?}", patterns);
+        return Ok(None);
+    }
+
+    std::fs::create_dir_all(ARTIFACTS_DIR)?;
+
+    let archive = format!("{}/{}.tar", ARTIFACTS_DIR, request_id);
+
+    protocol::tar::tar_files(workspace, &files, &archive)?;
+
+    info!("Collected {} artifacts into {}", files.len(), archive);
+
+    Ok(Some(archive))
+}
+
+fn prepare_workspace(
+    workspace: &str,
+    source: &WorkspaceSource,
+    compression: Compression,
+) -> Result<(), Box<dyn std::error::Error>> {
     if std::path::Path::new(workspace).exists() {
         std::fs::remove_dir_all(workspace)?;
     }
 
     std::fs::create_dir_all(workspace)?;
 
-    let tar_path = format!("{}/code.tar", workspace);
-    std::fs::write(&tar_path, data)?;
+    let limits = protocol::tar::UnpackLimits::default();
+
+    match source {
+        WorkspaceSource::Inline(data) => {
+            info!("Received workspace of {} bytes", data.len());
+
+            protocol::tar::unpack(&data[..], Path::new(workspace), &limits, compression)?;
+        }
+        WorkspaceSource::Archive(path) => {
+            info!("Using uploaded workspace archive {}", path);
 
-    protocol::tar::untar_workspace(&tar_path, workspace)?;
+            let file = std::fs::File::open(path)?;
+            protocol::tar::unpack(file, Path::new(workspace), &limits, compression)?;
+        }
+    }
 
     Ok(())
 }
 
+/// Resolves the entrypoint below the workspace, following symlinks. `None`
+/// unless it is a file inside the workspace, so that an absolute path, `..`
+/// or a link cannot make a run chmod and execute a file elsewhere.
+fn resolve_entrypoint(workspace: &str, entrypoint: &str) -> Option<PathBuf> {
+    let workspace = Path::new(workspace).canonicalize().ok()?;
+    let entrypoint = workspace.join(entrypoint).canonicalize().ok()?;
+
+    (entrypoint.starts_with(&workspace) && entrypoint.is_file()).then_some(entrypoint)
+}
+
 fn make_entrypoint_executable(entrypoint: &Path) -> Result<(), Box<dyn std::error::Error>> {
     let meta = std::fs::metadata(entrypoint)?;
     let mut perms = meta.permissions();
@@ -172,18 +641,16 @@ fn make_entrypoint_executable(entrypoint: &Path) -> Result<(), Box<dyn std::erro
     Ok(())
 }
 
-async fn run_program(
-    entrypoint: &Path,
-    workspace: &str,
-) -> Result<Output, Box<dyn std::error::Error>> {
+fn run_program(entrypoint: &Path, workspace: &str) -> Result<Child, Box<dyn std::error::Error>> {
     let child = tokio::process::Command::new("/bin/sh")
         .arg(entrypoint)
         .current_dir(workspace)
+        .stdin(std::process::Stdio::null())
         .stdout(std::process::Stdio::piped())
         .stderr(std::process::Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true)
         .spawn()?;
 
-    let out = child.wait_with_output().await?;
-
-    Ok(out)
+    Ok(child)
 }
@@ -39,4 +39,21 @@ pub fn mount_drives() {
         Ok(_) => info!("Mounted sysfs"),
         Err(_) => panic!("Failed to mount sysfs"),
     };
+
+    // Pseudo terminals for interactive sessions
+    if let Err(e) = std::fs::create_dir_all("/dev/pts") {
+        panic!("Failed to create /dev/pts: {}", e);
+    }
+
+    match mount(
+        Some("devpts"),
+        "/dev/pts",
+        Some("devpts"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("gid=5,mode=620,ptmxmode=666"),
+    ) {
+        Ok(_) => info!("Mounted devpts"),
+        Err(nix::errno::Errno::EBUSY) => info!("/dev/pts already mounted"),
+        Err(e) => panic!("Failed to mount devpts: {}", e),
+    };
 }
@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    os::unix::process::ExitStatusExt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use nix::{
+    sys::signal::{Signal, killpg},
+    unistd::Pid,
+};
+use protocol::{CommandOutput, Message, OutputChunk, OutputStream, ProcessExit, TerminalSize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::{Child, ChildStdin},
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+use crate::messaging::{self, Outbox};
+
+/// Upper bound for each of stdout and stderr buffered for a `CommandOutput`
+const MAX_OUTPUT_BYTES: usize = 1024 * 1024;
+
+const READ_BUFFER_SIZE: usize = 8192;
+
+/// Time a stopped process group gets to exit after SIGTERM before SIGKILL
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Time between SIGKILLs while a stopped process group holds on to its pipes
+const KILL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// SIGKILLs sent before the pipes are given up on. Whatever still holds them
+/// open by then has left the process group.
+const KILL_ATTEMPTS: u32 = 5;
+
+pub struct ExecOptions {
+    pub streaming: bool,
+    pub timeout: Option<Duration>,
+    /// Written to a piped stdin before any `Control::Input`
+    pub stdin: Option<Vec<u8>>,
+    /// Leave a piped stdin open for `Control::Input` until `Control::CloseInput`
+    pub keep_stdin_open: bool,
+}
+
+/// Host requests aimed at an already submitted request
+pub enum Control {
+    Cancel,
+    Signal(Signal),
+    Input(Vec<u8>),
+    CloseInput,
+    Resize(TerminalSize),
+    /// A numbered piece of an upload
+    Chunk {
+        seq: u64,
+        data: Vec<u8>,
+    },
+}
+
+/// Control channels of every request that has not finished yet, by request ID
+#[derive(Clone, Default)]
+pub struct Controls(Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Control>>>>);
+
+impl Controls {
+    pub fn register(&self, request_id: u64) -> mpsc::UnboundedReceiver<Control> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        self.0
+            .lock()
+            .expect("Failed to grab controls mutex")
+            .insert(request_id, tx);
+
+        rx
+    }
+
+    pub fn unregister(&self, request_id: u64) {
+        self.0
+            .lock()
+            .expect("Failed to grab controls mutex")
+            .remove(&request_id);
+    }
+
+    /// Returns false if no live request has this ID
+    pub fn send(&self, request_id: u64, control: Control) -> bool {
+        match self
+            .0
+            .lock()
+            .expect("Failed to grab controls mutex")
+            .get(&request_id)
+        {
+            Some(tx) => tx.send(control).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Collects the output of a running child and returns the final reply for
+/// the host, which the caller sends.
+///
+/// In streaming mode every read is forwarded as an `OutputChunk` and the run
+/// ends with a `ProcessExit`. Otherwise the output is buffered up to
+/// `MAX_OUTPUT_BYTES` per stream and returned as one `CommandOutput`.
+///
+/// The child must lead its own process group. If it outlives its timeout or
+/// is cancelled the whole group gets SIGTERM, followed by SIGKILL after
+/// `KILL_GRACE_PERIOD`, repeated until the pipes close or `KILL_ATTEMPTS` run
+/// out.
+pub async fn supervise(
+    outbox: &Outbox,
+    request_id: u64,
+    mut child: Child,
+    options: ExecOptions,
+    mut controls: mpsc::UnboundedReceiver<Control>,
+    started: Instant,
+) -> Result<Message, Box<dyn std::error::Error>> {
+    let mut collector = OutputCollector {
+        outbox,
+        request_id,
+        streaming: options.streaming,
+        seq: 0,
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        truncated: false,
+    };
+
+    let pgid = child
+        .id()
+        .map(|id| Pid::from_raw(id as i32))
+        .ok_or("child exited before supervision started")?;
+
+    // Stdin is written from its own task so a child that stops reading it
+    // cannot stall the output collection below
+    let mut stdin = child.stdin.take().map(|pipe| {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(feed_stdin(pipe, options.stdin, rx));
+        tx
+    });
+
+    if !options.keep_stdin_open {
+        stdin = None;
+    }
+
+    let mut stdout = child.stdout.take().ok_or("child stdout not piped")?;
+    let mut stderr = child.stderr.take().ok_or("child stderr not piped")?;
+
+    let mut stdout_buf = [0u8; READ_BUFFER_SIZE];
+    let mut stderr_buf = [0u8; READ_BUFFER_SIZE];
+
+    let mut stdout_open = true;
+    let mut stderr_open = true;
+    let mut status = None;
+
+    let deadline = options
+        .timeout
+        .map(|t| tokio::time::Instant::from_std(started) + t);
+    let mut stopper = Stopper::new(pgid, deadline);
+    let mut timed_out = false;
+    let mut cancelled = false;
+    let mut controls_open = true;
+
+    while stdout_open || stderr_open || status.is_none() {
+        tokio::select! {
+            n = stdout.read(&mut stdout_buf), if stdout_open => match n? {
+                0 => stdout_open = false,
+                n => collector.push(OutputStream::Stdout, &stdout_buf[..n]).await?,
+            },
+            n = stderr.read(&mut stderr_buf), if stderr_open => match n? {
+                0 => stderr_open = false,
+                n => collector.push(OutputStream::Stderr, &stderr_buf[..n]).await?,
+            },
+            s = child.wait(), if status.is_none() => status = Some(s?),
+            control = controls.recv(), if controls_open => match control {
+                Some(Control::Cancel) => {
+                    info!("Request {} cancelled", request_id);
+
+                    cancelled = true;
+                    stopper.stop_now();
+                }
+                Some(Control::Signal(signal)) => signal_group(pgid, signal),
+                Some(Control::Input(data)) => match &stdin {
+                    Some(tx) => {
+                        let _ = tx.send(data);
+                    }
+                    None => warn!("Stdin of request {} is closed", request_id),
+                },
+                Some(Control::CloseInput) => stdin = None,
+                Some(Control::Resize(_)) => warn!("Request {} has no terminal", request_id),
+                Some(Control::Chunk { .. }) => warn!("Request {} is not an upload", request_id),
+                None => controls_open = false,
+            },
+            _ = stopper.wait() => {
+                if !stopper.started() && !cancelled {
+                    timed_out = true;
+                }
+
+                stopper.escalate();
+
+                if stopper.gave_up() && status.is_some() {
+                    warn!("Request {} exited, leaving its output open", request_id);
+
+                    stdout_open = false;
+                    stderr_open = false;
+                }
+            }
+        }
+    }
+
+    let status = status.expect("loop exits only after the child was reaped");
+
+    info!("Process exited with status: {}", status);
+
+    let duration_ms = started.elapsed().as_millis() as u64;
+
+    let message = match collector.streaming {
+        true => Message::ProcessExit(ProcessExit {
+            exit_code: status.code(),
+            signal: status.signal(),
+            duration_ms,
+            timed_out,
+            cancelled,
+            artifacts: None,
+        }),
+        false => Message::CommandOutput(CommandOutput {
+            exit_code: status.code(),
+            signal: status.signal(),
+            stdout: collector.stdout,
+            stderr: collector.stderr,
+            duration_ms,
+            truncated: collector.truncated,
+            timed_out,
+            cancelled,
+            artifacts: None,
+        }),
+    };
+
+    Ok(message)
+}
+
+async fn feed_stdin(
+    mut pipe: ChildStdin,
+    initial: Option<Vec<u8>>,
+    mut chunks: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    if let Some(data) = initial
+        && let Err(e) = pipe.write_all(&data).await
+    {
+        return info!("Stopped writing stdin: {}", e);
+    }
+
+    while let Some(data) = chunks.recv().await {
+        if let Err(e) = pipe.write_all(&data).await {
+            return info!("Stopped writing stdin: {}", e);
+        }
+    }
+
+    // Dropping the pipe closes it and the child reads EOF
+}
+
+/// Stops a process group: SIGTERM first, then SIGKILL every `KILL_INTERVAL`
+/// if it is still around after `KILL_GRACE_PERIOD`
+pub struct Stopper {
+    pgid: Pid,
+    signal_at: Option<tokio::time::Instant>,
+    next_signal: Signal,
+    kills: u32,
+}
+
+impl Stopper {
+    /// Schedules the stop for `deadline`, or for never
+    pub fn new(pgid: Pid, deadline: Option<tokio::time::Instant>) -> Self {
+        Stopper {
+            pgid,
+            signal_at: deadline,
+            next_signal: Signal::SIGTERM,
+            kills: 0,
+        }
+    }
+
+    /// Starts stopping right away unless that already began
+    pub fn stop_now(&mut self) {
+        if !self.started() {
+            self.signal_at = Some(tokio::time::Instant::now());
+        }
+    }
+
+    pub fn started(&self) -> bool {
+        self.next_signal != Signal::SIGTERM
+    }
+
+    /// Resolves when the next signal is due
+    pub async fn wait(&self) {
+        match self.signal_at {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn escalate(&mut self) {
+        warn!("Sending {} to group {}", self.next_signal, self.pgid);
+
+        signal_group(self.pgid, self.next_signal);
+
+        let delay = match self.next_signal {
+            Signal::SIGTERM => KILL_GRACE_PERIOD,
+            _ => {
+                self.kills += 1;
+                KILL_INTERVAL
+            }
+        };
+
+        self.signal_at = Some(tokio::time::Instant::now() + delay);
+        self.next_signal = Signal::SIGKILL;
+    }
+
+    /// Whether `KILL_ATTEMPTS` SIGKILLs went out, so the output left open is
+    /// not worth waiting for
+    pub fn gave_up(&self) -> bool {
+        self.kills >= KILL_ATTEMPTS
+    }
+}
+
+pub fn signal_group(pgid: Pid, signal: Signal) {
+    match killpg(pgid, signal) {
+        Ok(_) => (),
+        // The whole group is already gone
+        Err(nix::errno::Errno::ESRCH) => (),
+        Err(e) => warn!("Failed to send {} to group {}: {}", signal, pgid, e),
+    }
+}
+
+struct OutputCollector<'a> {
+    outbox: &'a Outbox,
+    request_id: u64,
+    streaming: bool,
+    seq: u64,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    truncated: bool,
+}
+
+impl OutputCollector<'_> {
+    async fn push(
+        &mut self,
+        output: OutputStream,
+        data: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.streaming {
+            let chunk = OutputChunk {
+                stream: output,
+                seq: self.seq,
+                data: data.to_vec(),
+            };
+            self.seq += 1;
+
+            return messaging::reply(self.outbox, self.request_id, Message::OutputChunk(chunk))
+                .await;
+        }
+
+        let buf = match output {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+        };
+
+        let room = MAX_OUTPUT_BYTES - buf.len();
+        if data.len() > room {
+            self.truncated = true;
+        }
+
+        buf.extend_from_slice(&data[..data.len().min(room)]);
+
+        Ok(())
+    }
+}
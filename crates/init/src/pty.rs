@@ -0,0 +1,175 @@
+use std::{
+    os::fd::{AsRawFd, RawFd},
+    os::unix::process::ExitStatusExt,
+    process::Stdio,
+    time::Instant,
+};
+
+use nix::{
+    pty::{Winsize, openpty},
+    unistd::Pid,
+};
+use protocol::{
+    ErrorKind, Message, OutputChunk, OutputStream, ProcessExit, PtyOpen, RequestError, TerminalSize,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+use crate::{
+    messaging::{self, Outbox},
+    process::{self, Control, Stopper},
+};
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Runs an interactive program on a fresh pseudo terminal until it exits.
+///
+/// Terminal output is sent as `OutputChunk`s, keystrokes and window size
+/// changes arrive through `controls`. The program leads its own session, so
+/// `Cancel` and `Signal` reach everything started from it.
+pub async fn run_session(
+    outbox: &Outbox,
+    request_id: u64,
+    open: PtyOpen,
+    mut controls: mpsc::UnboundedReceiver<Control>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+
+    let pty = openpty(&winsize(open.size), None)?;
+
+    let mut command = tokio::process::Command::new(&open.command);
+    command
+        .args(&open.args)
+        .env("TERM", "xterm-256color")
+        .envs(&open.env)
+        .current_dir("/")
+        .stdin(Stdio::from(pty.slave.try_clone()?))
+        .stdout(Stdio::from(pty.slave.try_clone()?))
+        .stderr(Stdio::from(pty.slave))
+        .kill_on_drop(true);
+
+    // SAFETY: setsid and ioctl are async-signal-safe
+    unsafe {
+        command.pre_exec(|| {
+            nix::unistd::setsid()?;
+
+            // Make the pty, already on stdin, the controlling terminal
+            if libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+    }
+
+    let mut child = command
+        .spawn()
+        .map_err(|e| RequestError::new(ErrorKind::SpawnFailed, e.to_string()))?;
+
+    // The command holds our copies of the slave side. Reads from the master
+    // only report the end of the session once every copy is closed.
+    drop(command);
+
+    let pgid = child
+        .id()
+        .map(|id| Pid::from_raw(id as i32))
+        .ok_or("session exited before it started")?;
+
+    info!("Started pty session {} for {}", request_id, open.command);
+
+    let mut reader = tokio::fs::File::from_std(std::fs::File::from(pty.master.try_clone()?));
+    let mut writer = tokio::fs::File::from_std(std::fs::File::from(pty.master));
+
+    let mut buf = [0u8; READ_BUFFER_SIZE];
+    let mut seq = 0;
+
+    let mut output_open = true;
+    let mut controls_open = true;
+    let mut status = None;
+
+    let mut stopper = Stopper::new(pgid, None);
+    let mut cancelled = false;
+
+    while output_open || status.is_none() {
+        tokio::select! {
+            n = reader.read(&mut buf), if output_open => match n {
+                Ok(0) => output_open = false,
+                Ok(n) => {
+                    let chunk = OutputChunk {
+                        stream: OutputStream::Stdout,
+                        seq,
+                        data: buf[..n].to_vec(),
+                    };
+                    seq += 1;
+
+                    messaging::reply(outbox, request_id, Message::OutputChunk(chunk)).await?;
+                }
+                // The master reports EIO once the slave side is gone
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => output_open = false,
+                Err(e) => return Err(e.into()),
+            },
+            s = child.wait(), if status.is_none() => status = Some(s?),
+            control = controls.recv(), if controls_open => match control {
+                Some(Control::Input(data)) => {
+                    writer.write_all(&data).await?;
+                    writer.flush().await?;
+                }
+                Some(Control::CloseInput) => warn!("Pty session {} keeps its input open", request_id),
+                Some(Control::Resize(size)) => resize(writer.as_raw_fd(), size),
+                Some(Control::Chunk { .. }) => warn!("Pty session {} is not an upload", request_id),
+                Some(Control::Cancel) => {
+                    info!("Pty session {} cancelled", request_id);
+
+                    cancelled = true;
+                    stopper.stop_now();
+                }
+                Some(Control::Signal(signal)) => process::signal_group(pgid, signal),
+                None => controls_open = false,
+            },
+            _ = stopper.wait() => {
+                stopper.escalate();
+
+                if stopper.gave_up() && status.is_some() {
+                    warn!("Pty session {} exited, leaving its output open", request_id);
+                    output_open = false;
+                }
+            }
+        }
+    }
+
+    let status = status.expect("loop exits only after the child was reaped");
+
+    info!("Pty session {} exited with status: {}", request_id, status);
+
+    let exit = ProcessExit {
+        exit_code: status.code(),
+        signal: status.signal(),
+        duration_ms: started.elapsed().as_millis() as u64,
+        timed_out: false,
+        cancelled,
+        artifacts: None,
+    };
+
+    messaging::reply(outbox, request_id, Message::ProcessExit(exit)).await
+}
+
+fn winsize(size: TerminalSize) -> Winsize {
+    Winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+fn resize(master: RawFd, size: TerminalSize) {
+    let ws = winsize(size);
+
+    // SAFETY: TIOCSWINSZ only reads the winsize we pass in
+    if unsafe { libc::ioctl(master, libc::TIOCSWINSZ, &ws) } < 0 {
+        warn!("Failed to resize pty: {}", std::io::Error::last_os_error());
+    }
+}
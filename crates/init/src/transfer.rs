@@ -0,0 +1,280 @@
+use std::{
+    io::SeekFrom,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use protocol::{FileInfo, Message, UploadBegin, UploadComplete, tar::Compression};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+use crate::{
+    messaging::{self, Outbox},
+    process::Control,
+};
+
+/// Receives an upload into a partial file next to its destination and moves
+/// it into place once the checksum matches.
+///
+/// The partial file is named after the checksum and survives a dropped
+/// connection, so beginning the same upload again resumes after the last
+/// complete chunk. Chunks arrive through `controls`, the end of the upload
+/// as `Control::CloseInput`. A cancelled upload is discarded.
+pub async fn receive_upload(
+    outbox: &Outbox,
+    request_id: u64,
+    begin: UploadBegin,
+    controls: mpsc::UnboundedReceiver<Control>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let message = match receive(outbox, request_id, &begin, controls).await {
+        Ok(complete) => Message::UploadComplete(complete),
+        Err(e) => {
+            warn!("Upload {} to {} failed: {}", request_id, begin.path, e);
+
+            Message::TransferFailed {
+                reason: e.to_string(),
+            }
+        }
+    };
+
+    messaging::reply(outbox, request_id, message).await
+}
+
+async fn receive(
+    outbox: &Outbox,
+    request_id: u64,
+    begin: &UploadBegin,
+    mut controls: mpsc::UnboundedReceiver<Control>,
+) -> Result<UploadComplete, Box<dyn std::error::Error>> {
+    let path = Path::new(&begin.path);
+
+    if !path.is_absolute() || path.file_name().is_none() {
+        return Err(format!("Upload path {} is not an absolute file path", begin.path).into());
+    }
+
+    if begin.chunk_size == 0 {
+        return Err("Upload chunk size must not be zero".into());
+    }
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let partial = partial_path(path, &begin.sha256);
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&partial)
+        .await?;
+
+    // Keep whole chunks only, a torn last write is sent again
+    let kept = file.metadata().await?.len().min(begin.size);
+    let mut next_seq = kept / begin.chunk_size;
+    let mut written = next_seq * begin.chunk_size;
+
+    file.set_len(written).await?;
+    file.seek(SeekFrom::Start(written)).await?;
+
+    if written > 0 {
+        info!("Resuming upload to {} at {} bytes", begin.path, written);
+    }
+
+    messaging::reply(outbox, request_id, Message::UploadReady { next_seq }).await?;
+
+    loop {
+        match controls.recv().await {
+            Some(Control::Chunk { seq, data }) => {
+                if seq != next_seq {
+                    return Err(format!("Expected chunk {}, got {}", next_seq, seq).into());
+                }
+
+                if data.len() as u64 > begin.chunk_size || written + data.len() as u64 > begin.size
+                {
+                    return Err(format!("Chunk {} does not fit the upload", seq).into());
+                }
+
+                file.write_all(&data).await?;
+
+                written += data.len() as u64;
+                next_seq += 1;
+
+                messaging::reply(outbox, request_id, Message::UploadAck { seq }).await?;
+            }
+            Some(Control::CloseInput) => break,
+            Some(Control::Cancel) => {
+                drop(file);
+                tokio::fs::remove_file(&partial).await?;
+
+                return Err("Upload cancelled".into());
+            }
+            Some(_) => warn!("Upload {} only takes chunks", request_id),
+            None => return Err("Upload abandoned".into()),
+        }
+    }
+
+    file.sync_all().await?;
+    drop(file);
+
+    if written != begin.size {
+        return Err(format!("Received {} of {} bytes", written, begin.size).into());
+    }
+
+    let sha256 = protocol::transfer::sha256_file(&partial).await?;
+
+    if sha256 != begin.sha256 {
+        tokio::fs::remove_file(&partial).await?;
+
+        return Err(format!(
+            "Checksum mismatch: expected {}, got {}",
+            begin.sha256, sha256
+        )
+        .into());
+    }
+
+    tokio::fs::set_permissions(&partial, std::fs::Permissions::from_mode(begin.mode)).await?;
+    tokio::fs::rename(&partial, path).await?;
+
+    info!("Upload of {} bytes to {} complete", written, begin.path);
+
+    Ok(UploadComplete {
+        path: begin.path.clone(),
+        size: written,
+        sha256,
+    })
+}
+
+/// What a `FetchFile` or `FetchDir` asks for
+pub enum FetchTarget {
+    File(String),
+    Dir(String),
+}
+
+/// Sends a file, or a tarball of a directory, to the host in chunks read
+/// from disk. A `Cancel` stops the download between chunks.
+pub async fn send_download(
+    outbox: &Outbox,
+    request_id: u64,
+    target: FetchTarget,
+    mut controls: mpsc::UnboundedReceiver<Control>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let message = match send_target(outbox, request_id, &target, &mut controls).await {
+        Ok(_) => Message::DownloadEnd,
+        Err(e) => {
+            warn!("Download {} failed: {}", request_id, e);
+
+            Message::TransferFailed {
+                reason: e.to_string(),
+            }
+        }
+    };
+
+    messaging::reply(outbox, request_id, message).await
+}
+
+async fn send_target(
+    outbox: &Outbox,
+    request_id: u64,
+    target: &FetchTarget,
+    controls: &mut mpsc::UnboundedReceiver<Control>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match target {
+        FetchTarget::File(path) => send_file(outbox, request_id, path, controls).await,
+        FetchTarget::Dir(path) => {
+            let archive = format!("/tmp/fetch-{}.tar", request_id);
+
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&archive)?;
+
+            // Unlinked before packing, so fetching a directory that holds
+            // it, such as /tmp, does not pack the archive into itself
+            std::fs::remove_file(&archive)?;
+
+            let mut file = protocol::tar::pack_dir(Path::new(path), file, Compression::None)?;
+            std::io::Seek::seek(&mut file, SeekFrom::Start(0))?;
+
+            let file = tokio::fs::File::from_std(file);
+
+            send_open_file(outbox, request_id, &archive, file, controls).await
+        }
+    }
+}
+
+async fn send_file(
+    outbox: &Outbox,
+    request_id: u64,
+    path: &str,
+    controls: &mut mpsc::UnboundedReceiver<Control>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = tokio::fs::File::open(path).await?;
+
+    send_open_file(outbox, request_id, path, file, controls).await
+}
+
+/// Sends `file` from its start. `path` only names it in errors and logs.
+async fn send_open_file(
+    outbox: &Outbox,
+    request_id: u64,
+    path: &str,
+    mut file: tokio::fs::File,
+    controls: &mut mpsc::UnboundedReceiver<Control>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let meta = file.metadata().await?;
+
+    if !meta.is_file() {
+        return Err(format!("{} is not a file", path).into());
+    }
+
+    let sha256 = protocol::transfer::sha256_reader(&mut file).await?;
+    file.seek(SeekFrom::Start(0)).await?;
+
+    let chunk_size = protocol::transfer::DEFAULT_CHUNK_SIZE as u64;
+    let info = FileInfo {
+        size: meta.len(),
+        mode: meta.permissions().mode() & 0o7777,
+        sha256,
+        chunk_size,
+    };
+
+    info!("Sending {} ({} bytes)", path, info.size);
+
+    messaging::reply(outbox, request_id, Message::DownloadBegin(info)).await?;
+
+    let mut seq = 0;
+
+    loop {
+        if let Ok(Control::Cancel) = controls.try_recv() {
+            return Err("Download cancelled".into());
+        }
+
+        let mut data = Vec::with_capacity(chunk_size as usize);
+        (&mut file).take(chunk_size).read_to_end(&mut data).await?;
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        messaging::reply(outbox, request_id, Message::DownloadChunk { seq, data }).await?;
+
+        seq += 1;
+    }
+}
+
+fn partial_path(path: &Path, sha256: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tag: String = sha256
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .take(16)
+        .collect();
+
+    path.with_file_name(format!(".{}.{}.part", name, tag))
+}
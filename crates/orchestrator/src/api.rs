@@ -0,0 +1,1100 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::{
+        DefaultBodyLimit, Multipart, Path, Query, State,
+        multipart::Field,
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+    },
+    http::StatusCode,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{get, post},
+};
+use futures::{SinkExt, Stream, StreamExt};
+use protocol::api::{
+    ApiError, CreateVm, ExecEvent, JobCreated, JobStatus, PoolStats, RunRequest, RunResult,
+    VmHealth, VmInfo, WorkspaceRequest,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use tracing::{error, info, warn};
+
+use crate::{
+    health::HeartbeatConfig,
+    jobs::JobStore,
+    vm::VmResources,
+    vm_handle::{self, CommandStream, VmHandle},
+    vm_store::VmStore,
+};
+
+/// Most vCPUs Firecracker gives a VM
+const MAX_VCPU_COUNT: u32 = 32;
+
+/// Least memory a guest boots with
+const MIN_MEM_SIZE_MIB: u32 = 128;
+
+/// Largest workspace upload. The tarball is spooled to disk and, unless it is
+/// small, uploaded to the guest in chunks, so it is not bound by the frame
+/// size.
+const MAX_WORKSPACE_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Longest a one-shot run without a timeout of its own may take
+const DEFAULT_RUN_TIME: Duration = Duration::from_secs(10 * 60);
+
+/// Added to the run's timeout for booting the VM, uploading the workspace
+/// and reading back the artifacts. Past that the run is given up on and its
+/// VM torn down.
+const RUN_OVERHEAD: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct AppState {
+    pub store: Arc<Mutex<VmStore>>,
+    /// Applied to every VM created through the API
+    pub heartbeat: HeartbeatConfig,
+    pub jobs: Arc<Mutex<JobStore>>,
+}
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .route(
+            "/run",
+            post(run_once).layer(DefaultBodyLimit::max(MAX_WORKSPACE_SIZE)),
+        )
+        .route("/pool", get(pool_stats))
+        .route("/vms", get(list_vms).post(create_vm))
+        .route("/vms/{id}", get(get_vm).delete(delete_vm))
+        .route("/vms/{id}/health", get(vm_health))
+        .route("/vms/{id}/exec", post(exec))
+        .route("/vms/{id}/exec/stream", post(exec_sse))
+        .route("/vms/{id}/exec/ws", get(exec_ws))
+        .route(
+            "/vms/{id}/workspace",
+            post(run_workspace).layer(DefaultBodyLimit::max(MAX_WORKSPACE_SIZE)),
+        )
+        .route(
+            "/vms/{id}/jobs/{job_id}",
+            get(job_status).delete(cancel_job),
+        )
+        .route("/vms/{id}/pty", get(pty_session))
+        .route("/vms/{id}/fs/list", get(fs_list))
+        .route("/vms/{id}/fs/stat", get(fs_stat))
+        .route(
+            "/vms/{id}/fs/file",
+            get(fs_read).put(fs_write).delete(fs_remove),
+        )
+        .route("/vms/{id}/fs/mkdir", post(fs_mkdir))
+        .route("/vms/{id}/fs/rename", post(fs_rename))
+        .with_state(state)
+}
+
+async fn find_vm(state: &AppState, id: &str) -> Result<Arc<VmHandle>, Response> {
+    match state.store.lock().await.get_vm(id) {
+        Some(vm) => Ok(vm),
+        None => Err(error_response(
+            StatusCode::NOT_FOUND,
+            format!("No VM {}", id),
+        )),
+    }
+}
+
+fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    let error = ApiError {
+        kind: None,
+        message: message.into(),
+    };
+
+    (status, Json(error)).into_response()
+}
+
+/// Boots a VM in the background. Poll `GET /vms/{id}` until it is healthy.
+async fn create_vm(State(state): State<AppState>, body: Option<Json<CreateVm>>) -> Response {
+    let body = body.map(|Json(b)| b).unwrap_or_default();
+
+    let resources = match vm_resources(&body) {
+        Ok(resources) => resources,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    let pooled = state.store.lock().await.take_pooled(resources);
+
+    if let Some(vm) = pooled {
+        info!("Took {} from the pool", vm.id);
+        return (StatusCode::CREATED, Json(vm.info())).into_response();
+    }
+
+    let vm = match state
+        .store
+        .lock()
+        .await
+        .create_vm(resources, state.heartbeat)
+    {
+        Ok(vm) => vm,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+
+    if let Err(e) = vm.start_vm().await.map_err(|e| e.to_string()) {
+        state.store.lock().await.remove_vm(&vm.id);
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, e);
+    }
+
+    info!("Created {} with {:?}", vm.id, resources);
+
+    (StatusCode::CREATED, Json(vm.info())).into_response()
+}
+
+/// Fills in the defaults and checks the requested resources are bootable
+fn vm_resources(body: &CreateVm) -> Result<VmResources, String> {
+    let defaults = VmResources::default();
+    let resources = VmResources {
+        vcpu_count: body.vcpu_count.unwrap_or(defaults.vcpu_count),
+        mem_size_mib: body.mem_size_mib.unwrap_or(defaults.mem_size_mib),
+    };
+
+    if !(1..=MAX_VCPU_COUNT).contains(&resources.vcpu_count) {
+        return Err(format!(
+            "vcpu_count must be between 1 and {}",
+            MAX_VCPU_COUNT
+        ));
+    }
+
+    if resources.mem_size_mib < MIN_MEM_SIZE_MIB {
+        return Err(format!(
+            "mem_size_mib must be at least {}",
+            MIN_MEM_SIZE_MIB
+        ));
+    }
+
+    Ok(resources)
+}
+
+async fn pool_stats(State(state): State<AppState>) -> Json<PoolStats> {
+    Json(state.store.lock().await.pool_stats())
+}
+
+async fn list_vms(State(state): State<AppState>) -> Json<Vec<VmInfo>> {
+    let vms = state.store.lock().await.list();
+
+    Json(vms.iter().map(|vm| vm.info()).collect())
+}
+
+async fn get_vm(Path(id): Path<String>, State(state): State<AppState>) -> Response {
+    match find_vm(&state, &id).await {
+        Ok(vm) => Json(vm.info()).into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn vm_health(Path(id): Path<String>, State(state): State<AppState>) -> Response {
+    match find_vm(&state, &id).await {
+        Ok(vm) => Json(VmHealth {
+            health: vm.health(),
+        })
+        .into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Tears the VM down and waits until it is gone
+async fn delete_vm(Path(id): Path<String>, State(state): State<AppState>) -> Response {
+    // Removed first so no new requests reach it during the teardown
+    let vm = match state.store.lock().await.remove_vm(&id) {
+        Some(vm) => vm,
+        None => return error_response(StatusCode::NOT_FOUND, format!("No VM {}", id)),
+    };
+
+    state.jobs.lock().await.remove_vm(&id);
+
+    match vm.shutdown().await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// Runs a workspace tarball on a VM of its own, taken from the pool or
+/// booted for it, then recycles or destroys the VM.
+/// The form is that of `POST /vms/{id}/workspace`, with a `RunRequest` as
+/// its options. Replies with a `RunResult`.
+async fn run_once(State(state): State<AppState>, multipart: Multipart) -> Response {
+    let (options, archive) = match read_workspace_form::<RunRequest>(multipart).await {
+        Ok(form) => form,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    let resources = match vm_resources(&options.vm) {
+        Ok(resources) => resources,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    // A client that disconnects drops this handler, not the spawned task,
+    // so the VM is torn down either way
+    let run = tokio::spawn(run_ephemeral(state, resources, archive, options.workspace));
+
+    match run.await {
+        Ok(Ok(result)) => Json(result).into_response(),
+        Ok(Err((status, error))) => (status, Json(error)).into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+async fn run_ephemeral(
+    state: AppState,
+    resources: VmResources,
+    archive: SpooledArchive,
+    request: WorkspaceRequest,
+) -> Result<RunResult, (StatusCode, ApiError)> {
+    let (vm, pooled) = {
+        let mut store = state.store.lock().await;
+
+        match store.lend_pooled(resources) {
+            Some(vm) => (vm, true),
+            None => {
+                let vm = store.create_vm(resources, state.heartbeat).map_err(|e| {
+                    let error = ApiError {
+                        kind: None,
+                        message: e,
+                    };
+
+                    (StatusCode::SERVICE_UNAVAILABLE, error)
+                })?;
+
+                (vm, false)
+            }
+        }
+    };
+
+    if pooled {
+        info!("Took {} from the pool for a one-shot run", vm.id);
+    } else {
+        info!("Created {} for a one-shot run", vm.id);
+    }
+
+    let deadline = request
+        .timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_RUN_TIME)
+        + RUN_OVERHEAD;
+
+    // In its own task so that even a panicking run is followed by the
+    // teardown
+    let mut run = {
+        let vm = vm.clone();
+
+        tokio::spawn(async move {
+            if !pooled {
+                vm.boot().await.map_err(|e| run_error(&*e))?;
+            }
+
+            vm.run_once(&archive.path, request)
+                .await
+                .map_err(|e| run_error(&*e))
+        })
+    };
+
+    let result = match tokio::time::timeout(deadline, &mut run).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => {
+            let error = ApiError {
+                kind: None,
+                message: e.to_string(),
+            };
+
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error))
+        }
+        Err(_) => {
+            run.abort();
+
+            let error = ApiError {
+                kind: Some(protocol::ErrorKind::Timeout),
+                message: format!("Run did not finish within {:?}", deadline),
+            };
+
+            Err((StatusCode::SERVICE_UNAVAILABLE, error))
+        }
+    };
+
+    // A failed run may have left the guest busy, so only clean ones recycle,
+    // and only once the guest is reset
+    let recycle = result.is_ok() && state.store.lock().await.can_recycle(&vm.id);
+
+    let reusable = recycle
+        && match vm.reset().await {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Failed to reset {}, destroying it: {}", vm.id, e);
+                false
+            }
+        };
+
+    let released = state.store.lock().await.release_vm(&vm.id, reusable);
+
+    // Jobs started on it while it was handed out belong to that run
+    state.jobs.lock().await.remove_vm(&vm.id);
+
+    match released {
+        Some(vm) => {
+            if let Err(e) = vm.shutdown().await {
+                error!("Failed to tear down {}: {}", vm.id, e);
+            }
+        }
+        None => info!("Recycled {} into the pool", vm.id),
+    }
+
+    result
+}
+
+#[derive(Deserialize)]
+struct RunParams {
+    /// Reply with a job ID right away instead of waiting for the result
+    #[serde(default)]
+    detach: bool,
+}
+
+/// Runs a command and replies with its `CommandOutput`
+async fn exec(
+    Path(id): Path<String>,
+    Query(params): Query<RunParams>,
+    State(state): State<AppState>,
+    Json(mut cmd): Json<protocol::RunCommand>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    cmd.stream_output = false;
+
+    match vm.submit_command(cmd).await.map_err(|e| e.to_string()) {
+        Ok(replies) => run_reply(&state, &vm, replies, params.detach, None).await,
+        Err(e) => error_response(StatusCode::BAD_GATEWAY, e),
+    }
+}
+
+/// Runs the entrypoint of a workspace tarball and replies with its
+/// `CommandOutput`. The form has a JSON `WorkspaceRequest` in its `options`
+/// part and the tarball in its `archive` part.
+async fn run_workspace(
+    Path(id): Path<String>,
+    Query(params): Query<RunParams>,
+    State(state): State<AppState>,
+    multipart: Multipart,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    let (options, archive) = match read_workspace_form::<WorkspaceRequest>(multipart).await {
+        Ok(form) => form,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    let source = match vm.workspace_source(&archive.path).await {
+        Ok(source) => source,
+        Err(e) => return error_response(StatusCode::BAD_GATEWAY, e.to_string()),
+    };
+
+    let uploaded = vm_handle::uploaded_path(&source);
+
+    let cmd = protocol::WorkspaceRunOptions {
+        source,
+        entrypoint: options.entrypoint,
+        stream_output: false,
+        timeout_ms: options.timeout_ms,
+        artifacts: options.artifacts,
+        compression: options.compression,
+    };
+
+    match vm.submit_workspace(cmd).await.map_err(|e| e.to_string()) {
+        Ok(replies) => run_reply(&state, &vm, replies, params.detach, uploaded).await,
+        Err(e) => {
+            vm.discard_upload(uploaded).await;
+            error_response(StatusCode::BAD_GATEWAY, e)
+        }
+    }
+}
+
+/// A workspace tarball from a form, written to disk as it arrives rather
+/// than held in memory. Removed when dropped.
+struct SpooledArchive {
+    path: PathBuf,
+}
+
+impl SpooledArchive {
+    async fn receive(mut field: Field<'_>) -> Result<Self, String> {
+        let archive = SpooledArchive {
+            path: std::env::temp_dir()
+                .join(format!("secex-workspace-{}.tar", uuid::Uuid::new_v4())),
+        };
+
+        let mut file = tokio::fs::File::create(&archive.path)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        while let Some(chunk) = field.chunk().await.map_err(|e| e.to_string())? {
+            file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+        }
+
+        file.flush().await.map_err(|e| e.to_string())?;
+
+        Ok(archive)
+    }
+}
+
+impl Drop for SpooledArchive {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            error!("Error removing {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Reads the JSON `options` part and the `archive` part of a form
+async fn read_workspace_form<T: DeserializeOwned>(
+    mut multipart: Multipart,
+) -> Result<(T, SpooledArchive), String> {
+    let mut options = None;
+    let mut archive = None;
+
+    while let Some(field) = multipart.next_field().await.map_err(|e| e.to_string())? {
+        match field.name() {
+            Some("options") => {
+                let data = field.bytes().await.map_err(|e| e.to_string())?;
+                let parsed =
+                    serde_json::from_slice(&data).map_err(|e| format!("Invalid options: {}", e))?;
+                options = Some(parsed);
+            }
+            Some("archive") => archive = Some(SpooledArchive::receive(field).await?),
+            _ => (),
+        }
+    }
+
+    match (options, archive) {
+        (Some(options), Some(archive)) => Ok((options, archive)),
+        (None, _) => Err("Missing options part".to_string()),
+        (_, None) => Err("Missing archive part".to_string()),
+    }
+}
+
+/// Waits for the output of a submitted run, or hands that to a job and
+/// replies with its ID when detached
+async fn run_reply(
+    state: &AppState,
+    vm: &Arc<VmHandle>,
+    replies: CommandStream,
+    detach: bool,
+    uploaded: Option<String>,
+) -> Response {
+    if !detach {
+        let response = match replies.output().await {
+            Ok(output) => Json(output).into_response(),
+            Err(e) => {
+                let (status, error) = run_error(&*e);
+                (status, Json(error)).into_response()
+            }
+        };
+
+        vm.discard_upload(uploaded).await;
+        return response;
+    }
+
+    let job_id = state.jobs.lock().await.start(&vm.id, replies.request_id);
+
+    let jobs = state.jobs.clone();
+    let job = job_id.clone();
+    let vm = vm.clone();
+
+    tokio::spawn(async move {
+        let status = match replies.output().await {
+            Ok(output) => JobStatus::Finished { output },
+            Err(e) => JobStatus::Failed {
+                error: run_error(&*e).1,
+            },
+        };
+
+        jobs.lock().await.finish(&job, status);
+        vm.discard_upload(uploaded).await;
+    });
+
+    (StatusCode::ACCEPTED, Json(JobCreated { job_id })).into_response()
+}
+
+/// Status and body for a failed run. Failures the guest reported keep their
+/// kind, anything else means the guest could not be reached.
+fn run_error(e: &(dyn std::error::Error + 'static)) -> (StatusCode, ApiError) {
+    use protocol::{ErrorKind, RequestError};
+
+    match e.downcast_ref::<RequestError>() {
+        Some(re) => {
+            let status = match re.kind {
+                ErrorKind::EntrypointNotFound
+                | ErrorKind::ExtractFailed
+                | ErrorKind::SpawnFailed => StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorKind::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+                ErrorKind::Unsupported => StatusCode::NOT_IMPLEMENTED,
+                ErrorKind::Internal => StatusCode::BAD_GATEWAY,
+            };
+
+            let error = ApiError {
+                kind: Some(re.kind),
+                message: re.message.clone(),
+            };
+
+            (status, error)
+        }
+        None => {
+            let error = ApiError {
+                kind: None,
+                message: e.to_string(),
+            };
+
+            (StatusCode::BAD_GATEWAY, error)
+        }
+    }
+}
+
+async fn job_status(
+    Path((id, job_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    match state.jobs.lock().await.status(&id, &job_id) {
+        Some(status) => Json(status).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, format!("No job {}", job_id)),
+    }
+}
+
+/// Cancels a running job. Its status turns to `finished` with `cancelled`
+/// set once the guest has stopped it.
+async fn cancel_job(
+    Path((id, job_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    let request_id = match state.jobs.lock().await.running_request(&id, &job_id) {
+        Some(request_id) => request_id,
+        None => {
+            return error_response(StatusCode::NOT_FOUND, format!("No running job {}", job_id));
+        }
+    };
+
+    match vm.cancel(request_id).await {
+        Ok(_) => StatusCode::ACCEPTED.into_response(),
+        Err(e) => error_response(StatusCode::BAD_GATEWAY, e.to_string()),
+    }
+}
+
+/// Runs a command and streams its `ExecEvent`s as server-sent events, one
+/// JSON event per `data` line
+async fn exec_sse(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(cmd): Json<protocol::RunCommand>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    let replies = match vm.stream_command(cmd).await.map_err(|e| e.to_string()) {
+        Ok(replies) => replies,
+        Err(e) => return error_response(StatusCode::BAD_GATEWAY, e),
+    };
+
+    let events = exec_events(vm, replies).map(|event| Event::default().json_data(event));
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn exec_ws(
+    ws: WebSocketUpgrade,
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    ws.on_upgrade(move |socket| bridge_exec(socket, vm))
+}
+
+/// Runs the `RunCommand` sent in the first text frame and answers with its
+/// `ExecEvent`s as text frames. Closing the socket early cancels the run.
+async fn bridge_exec(socket: WebSocket, vm: Arc<VmHandle>) {
+    let (mut sink, mut source) = socket.split();
+
+    let cmd = loop {
+        match source.next().await {
+            Some(Ok(WsMessage::Text(text))) => match serde_json::from_str(&text) {
+                Ok(cmd) => break cmd,
+                Err(e) => {
+                    let event = ExecEvent::Error(ApiError {
+                        kind: None,
+                        message: format!("Invalid command: {}", e),
+                    });
+
+                    let _ = send_event(&mut sink, &event).await;
+                    let _ = sink.close().await;
+                    return;
+                }
+            },
+            Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return,
+            Some(Ok(_)) => (),
+        }
+    };
+
+    let replies = match vm.stream_command(cmd).await.map_err(|e| run_error(&*e).1) {
+        Ok(replies) => replies,
+        Err(error) => {
+            error!(
+                "Failed to start streamed exec on {}: {}",
+                vm.id, error.message
+            );
+
+            let _ = send_event(&mut sink, &ExecEvent::Error(error)).await;
+            let _ = sink.close().await;
+            return;
+        }
+    };
+
+    let events = exec_events(vm.clone(), replies);
+    futures::pin_mut!(events);
+
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => {
+                    if send_event(&mut sink, &event).await.is_err() {
+                        return;
+                    }
+                }
+                None => break,
+            },
+            incoming = source.next() => match incoming {
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => {
+                    return info!("Exec client on {} went away", vm.id);
+                }
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+
+    let _ = sink.close().await;
+}
+
+async fn send_event(
+    sink: &mut futures::stream::SplitSink<WebSocket, WsMessage>,
+    event: &ExecEvent,
+) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(event).expect("Failed to encode exec event");
+
+    sink.send(WsMessage::Text(text.into())).await
+}
+
+/// Turns the replies of a streamed run into `ExecEvent`s. The last event
+/// is always an `Exit` or an `Error`. Dropping the stream before then
+/// cancels the run.
+fn exec_events(vm: Arc<VmHandle>, replies: CommandStream) -> impl Stream<Item = ExecEvent> {
+    let guard = CancelOnDrop {
+        request_id: replies.request_id,
+        vm,
+        armed: true,
+    };
+
+    futures::stream::unfold(Some((replies, guard)), |state| async move {
+        let (mut replies, mut guard) = state?;
+
+        let event = match replies.next().await {
+            Some(protocol::Message::OutputChunk(chunk)) => {
+                return Some((ExecEvent::Output(chunk), Some((replies, guard))));
+            }
+            Some(protocol::Message::ProcessExit(exit)) => ExecEvent::Exit(exit),
+            Some(protocol::Message::Error { kind, message, .. }) => ExecEvent::Error(ApiError {
+                kind: Some(kind),
+                message,
+            }),
+            Some(m) => ExecEvent::Error(ApiError {
+                kind: None,
+                message: format!("Unexpected reply from guest: {:?}", m),
+            }),
+            None => ExecEvent::Error(ApiError {
+                kind: None,
+                message: "VM closed the request without replying".to_string(),
+            }),
+        };
+
+        guard.armed = false;
+
+        Some((event, None))
+    })
+}
+
+/// Cancels a streamed run whose HTTP client went away before it finished
+struct CancelOnDrop {
+    vm: Arc<VmHandle>,
+    request_id: u64,
+    armed: bool,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        let vm = self.vm.clone();
+        let request_id = self.request_id;
+
+        tokio::spawn(async move {
+            if let Err(e) = vm.cancel(request_id).await {
+                error!(
+                    "Failed to cancel request {} on {}: {}",
+                    request_id, vm.id, e
+                );
+            }
+        });
+    }
+}
+
+#[derive(Deserialize)]
+struct PtyParams {
+    #[serde(default = "default_shell")]
+    command: String,
+    #[serde(default = "default_rows")]
+    rows: u16,
+    #[serde(default = "default_cols")]
+    cols: u16,
+}
+
+fn default_shell() -> String {
+    "/bin/sh".to_string()
+}
+
+fn default_rows() -> u16 {
+    24
+}
+
+fn default_cols() -> u16 {
+    80
+}
+
+/// Text frames of a pty WebSocket. Terminal bytes travel in binary frames.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PtyFrame {
+    Resize {
+        rows: u16,
+        cols: u16,
+    },
+    Exit {
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+    },
+    Error {
+        kind: protocol::ErrorKind,
+        message: String,
+    },
+}
+
+async fn pty_session(
+    ws: WebSocketUpgrade,
+    Path(id): Path<String>,
+    Query(params): Query<PtyParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    ws.on_upgrade(move |socket| bridge_pty(socket, vm, params))
+}
+
+/// Connects a WebSocket to a new pty session until either side closes.
+/// A client that goes away cancels the session.
+async fn bridge_pty(socket: WebSocket, vm: Arc<VmHandle>, params: PtyParams) {
+    let open = protocol::PtyOpen {
+        command: params.command,
+        args: Vec::new(),
+        env: HashMap::new(),
+        size: protocol::TerminalSize {
+            rows: params.rows,
+            cols: params.cols,
+        },
+    };
+
+    let mut session = match vm.open_pty(open).await {
+        Ok(s) => s,
+        Err(e) => return error!("Failed to open pty on {}: {}", vm.id, e),
+    };
+
+    let session_id = session.request_id;
+    let (mut sink, mut source) = socket.split();
+
+    info!("Pty session {} opened on {}", session_id, vm.id);
+
+    loop {
+        tokio::select! {
+            msg = session.next() => match msg {
+                Some(protocol::Message::OutputChunk(chunk)) => {
+                    if sink.send(WsMessage::Binary(chunk.data.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Some(protocol::Message::ProcessExit(exit)) => {
+                    let frame = PtyFrame::Exit {
+                        exit_code: exit.exit_code,
+                        signal: exit.signal,
+                    };
+
+                    let text = serde_json::to_string(&frame).expect("Failed to encode exit frame");
+                    let _ = sink.send(WsMessage::Text(text.into())).await;
+                    let _ = sink.close().await;
+
+                    return info!("Pty session {} on {} exited", session_id, vm.id);
+                }
+                Some(protocol::Message::Error { kind, message, .. }) => {
+                    error!("Pty session {} on {} failed: {}", session_id, vm.id, message);
+
+                    let frame = PtyFrame::Error { kind, message };
+                    let text = serde_json::to_string(&frame).expect("Failed to encode error frame");
+                    let _ = sink.send(WsMessage::Text(text.into())).await;
+                    let _ = sink.close().await;
+
+                    return;
+                }
+                Some(m) => info!("Unexpected pty message from {}: {:?}", vm.id, m),
+                None => break,
+            },
+            incoming = source.next() => match incoming {
+                Some(Ok(WsMessage::Binary(data))) => {
+                    if let Err(e) = vm.pty_input(session_id, data.to_vec()).await {
+                        error!("Failed to forward pty input to {}: {}", vm.id, e);
+                    }
+                }
+                Some(Ok(WsMessage::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(PtyFrame::Resize { rows, cols }) => {
+                        let size = protocol::TerminalSize { rows, cols };
+
+                        if let Err(e) = vm.pty_resize(session_id, size).await {
+                            error!("Failed to resize pty on {}: {}", vm.id, e);
+                        }
+                    }
+                    _ => info!("Ignoring pty frame: {}", text.as_str()),
+                },
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => {
+                    info!("Pty client for session {} went away", session_id);
+
+                    if let Err(e) = vm.cancel(session_id).await {
+                        error!("Failed to cancel pty session on {}: {}", vm.id, e);
+                    }
+                    break;
+                }
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FsPath {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct ReadParams {
+    path: String,
+    #[serde(default)]
+    offset: u64,
+    #[serde(default = "default_read_length")]
+    length: u64,
+}
+
+fn default_read_length() -> u64 {
+    u64::MAX
+}
+
+#[derive(Deserialize)]
+struct WriteParams {
+    path: String,
+    #[serde(default)]
+    append: bool,
+    mode: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct RemoveParams {
+    path: String,
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[derive(Deserialize)]
+struct MkdirBody {
+    path: String,
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[derive(Deserialize)]
+struct RenameBody {
+    from: String,
+    to: String,
+}
+
+/// Size of the whole file, sent along with a possibly partial read
+const FILE_SIZE_HEADER: &str = "x-file-size";
+
+async fn fs_list(
+    Path(id): Path<String>,
+    Query(params): Query<FsPath>,
+    State(state): State<AppState>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    match vm.list_dir(&params.path).await {
+        Ok(entries) => Json(entries).into_response(),
+        Err(e) => fs_error_response(e),
+    }
+}
+
+async fn fs_stat(
+    Path(id): Path<String>,
+    Query(params): Query<FsPath>,
+    State(state): State<AppState>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    match vm.stat(&params.path).await {
+        Ok(stat) => Json(stat).into_response(),
+        Err(e) => fs_error_response(e),
+    }
+}
+
+async fn fs_read(
+    Path(id): Path<String>,
+    Query(params): Query<ReadParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    match vm
+        .read_file(&params.path, params.offset, params.length)
+        .await
+    {
+        Ok((data, size)) => ([(FILE_SIZE_HEADER, size.to_string())], data).into_response(),
+        Err(e) => fs_error_response(e),
+    }
+}
+
+async fn fs_write(
+    Path(id): Path<String>,
+    Query(params): Query<WriteParams>,
+    State(state): State<AppState>,
+    body: Bytes,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    match vm
+        .write_file(&params.path, body.to_vec(), params.append, params.mode)
+        .await
+    {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => fs_error_response(e),
+    }
+}
+
+async fn fs_remove(
+    Path(id): Path<String>,
+    Query(params): Query<RemoveParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    match vm.remove(&params.path, params.recursive).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => fs_error_response(e),
+    }
+}
+
+async fn fs_mkdir(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(body): Json<MkdirBody>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    match vm.create_dir(&body.path, body.recursive).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => fs_error_response(e),
+    }
+}
+
+async fn fs_rename(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(body): Json<RenameBody>,
+) -> Response {
+    let vm = match find_vm(&state, &id).await {
+        Ok(vm) => vm,
+        Err(response) => return response,
+    };
+
+    match vm.rename(&body.from, &body.to).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => fs_error_response(e),
+    }
+}
+
+/// Maps guest filesystem errors to their HTTP counterparts. Anything else
+/// means the guest could not be reached.
+fn fs_error_response(e: Box<dyn std::error::Error>) -> Response {
+    use protocol::fs::{FsError, FsErrorKind};
+
+    let status = match e.downcast_ref::<FsError>().map(|e| e.kind) {
+        Some(FsErrorKind::NotFound) => StatusCode::NOT_FOUND,
+        Some(FsErrorKind::PermissionDenied) => StatusCode::FORBIDDEN,
+        Some(FsErrorKind::AlreadyExists) | Some(FsErrorKind::DirectoryNotEmpty) => {
+            StatusCode::CONFLICT
+        }
+        Some(FsErrorKind::NotADirectory)
+        | Some(FsErrorKind::IsADirectory)
+        | Some(FsErrorKind::InvalidInput) => StatusCode::BAD_REQUEST,
+        Some(FsErrorKind::Other) => StatusCode::INTERNAL_SERVER_ERROR,
+        None => {
+            let (status, error) = run_error(&*e);
+            return (status, Json(error)).into_response();
+        }
+    };
+
+    error_response(status, e.to_string())
+}
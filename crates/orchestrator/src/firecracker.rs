@@ -88,6 +88,7 @@ impl FirecrackerConfig {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn fill_values(
         &mut self,
         boot_args: &str,
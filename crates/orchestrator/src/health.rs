@@ -0,0 +1,80 @@
+use std::{str::FromStr, time::Duration};
+
+pub use protocol::api::Health;
+
+/// Milliseconds between two pings to a guest
+const INTERVAL_ENV: &str = "SECEX_HEARTBEAT_INTERVAL_MS";
+
+/// Unanswered pings in a row after which a guest counts as unhealthy
+const MAX_MISSED_ENV: &str = "SECEX_HEARTBEAT_MAX_MISSED";
+
+/// What to do with an unhealthy guest: `restart`, `destroy` or `alert`
+const RECOVERY_ENV: &str = "SECEX_RECOVERY_ACTION";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryAction {
+    /// Kills the VM and boots it again from a fresh rootfs
+    Restart,
+    /// Kills the VM and leaves it stopped
+    Destroy,
+    /// Only logs, the VM keeps running and becomes healthy again if it
+    /// answers a later ping
+    Alert,
+}
+
+impl FromStr for RecoveryAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restart" => Ok(RecoveryAction::Restart),
+            "destroy" => Ok(RecoveryAction::Destroy),
+            "alert" => Ok(RecoveryAction::Alert),
+            other => Err(format!("Unknown recovery action: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub max_missed: u32,
+    pub recovery: RecoveryAction,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(5),
+            max_missed: 3,
+            recovery: RecoveryAction::Restart,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// The defaults, overridden by whichever heartbeat variables are set in
+    /// the environment
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = HeartbeatConfig::default();
+
+        if let Ok(ms) = std::env::var(INTERVAL_ENV) {
+            let ms: u64 = ms
+                .parse()
+                .map_err(|e| format!("Invalid {}: {}", INTERVAL_ENV, e))?;
+            config.interval = Duration::from_millis(ms.max(1));
+        }
+
+        if let Ok(n) = std::env::var(MAX_MISSED_ENV) {
+            config.max_missed = n
+                .parse()
+                .map_err(|e| format!("Invalid {}: {}", MAX_MISSED_ENV, e))?;
+        }
+
+        if let Ok(action) = std::env::var(RECOVERY_ENV) {
+            config.recovery = action.parse()?;
+        }
+
+        Ok(config)
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::{HashMap, VecDeque};
+
+use protocol::api::JobStatus;
+
+struct Job {
+    vm_id: String,
+    /// Guest request running the job, for cancelling it
+    request_id: u64,
+    status: JobStatus,
+}
+
+/// Finished jobs kept for their status, across all VMs
+const MAX_FINISHED_JOBS: usize = 1024;
+
+/// Detached runs, kept with their result until their VM is deleted or newer
+/// jobs push them out
+pub struct JobStore {
+    jobs: HashMap<String, Job>,
+    /// IDs of finished jobs, oldest first
+    finished: VecDeque<String>,
+    max_finished: usize,
+}
+
+impl Default for JobStore {
+    fn default() -> Self {
+        JobStore {
+            jobs: HashMap::new(),
+            finished: VecDeque::new(),
+            max_finished: MAX_FINISHED_JOBS,
+        }
+    }
+}
+
+impl JobStore {
+    /// Records a running job and returns its ID
+    pub fn start(&mut self, vm_id: &str, request_id: u64) -> String {
+        let job_id = uuid::Uuid::new_v4().to_string();
+
+        self.jobs.insert(
+            job_id.clone(),
+            Job {
+                vm_id: vm_id.to_string(),
+                request_id,
+                status: JobStatus::Running,
+            },
+        );
+
+        job_id
+    }
+
+    /// Records the result of a job, evicting the oldest finished jobs over
+    /// the limit
+    pub fn finish(&mut self, job_id: &str, status: JobStatus) {
+        let Some(job) = self.jobs.get_mut(job_id) else {
+            return;
+        };
+
+        if matches!(job.status, JobStatus::Running) {
+            self.finished.push_back(job_id.to_string());
+        }
+
+        job.status = status;
+
+        while self.finished.len() > self.max_finished {
+            if let Some(oldest) = self.finished.pop_front() {
+                self.jobs.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn status(&self, vm_id: &str, job_id: &str) -> Option<JobStatus> {
+        self.find(vm_id, job_id).map(|job| job.status.clone())
+    }
+
+    /// Guest request of a job that is still running
+    pub fn running_request(&self, vm_id: &str, job_id: &str) -> Option<u64> {
+        self.find(vm_id, job_id)
+            .filter(|job| matches!(job.status, JobStatus::Running))
+            .map(|job| job.request_id)
+    }
+
+    pub fn remove_vm(&mut self, vm_id: &str) {
+        self.jobs.retain(|_, job| job.vm_id != vm_id);
+        self.finished.retain(|id| self.jobs.contains_key(id));
+    }
+
+    fn find(&self, vm_id: &str, job_id: &str) -> Option<&Job> {
+        self.jobs.get(job_id).filter(|job| job.vm_id == vm_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::api::ApiError;
+
+    use super::*;
+
+    fn failed() -> JobStatus {
+        JobStatus::Failed {
+            error: ApiError {
+                kind: None,
+                message: "boom".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_job_lifecycle() {
+        let mut jobs = JobStore::default();
+        let id = jobs.start("vm-1", 7);
+
+        assert!(matches!(jobs.status("vm-1", &id), Some(JobStatus::Running)));
+        assert_eq!(jobs.running_request("vm-1", &id), Some(7));
+
+        // Jobs are only found under their own VM
+        assert!(jobs.status("vm-2", &id).is_none());
+        assert!(jobs.running_request("vm-2", &id).is_none());
+
+        jobs.finish(&id, failed());
+
+        assert!(matches!(
+            jobs.status("vm-1", &id),
+            Some(JobStatus::Failed { .. })
+        ));
+
+        // A finished job has nothing left to cancel
+        assert!(jobs.running_request("vm-1", &id).is_none());
+
+        jobs.remove_vm("vm-1");
+        assert!(jobs.status("vm-1", &id).is_none());
+        assert!(jobs.finished.is_empty());
+    }
+
+    #[test]
+    fn test_oldest_finished_jobs_are_evicted() {
+        let mut jobs = JobStore {
+            max_finished: 2,
+            ..Default::default()
+        };
+
+        let ids: Vec<_> = (0..3).map(|i| jobs.start("vm-1", i)).collect();
+        let running = jobs.start("vm-1", 3);
+
+        for id in &ids {
+            jobs.finish(id, failed());
+        }
+
+        assert!(jobs.status("vm-1", &ids[0]).is_none());
+        assert!(jobs.status("vm-1", &ids[1]).is_some());
+        assert!(jobs.status("vm-1", &ids[2]).is_some());
+
+        // Running jobs do not count against the limit
+        assert_eq!(jobs.running_request("vm-1", &running), Some(3));
+
+        // Finishing twice does not take up another place
+        jobs.finish(&ids[2], failed());
+        assert_eq!(jobs.finished.len(), 2);
+        assert!(jobs.status("vm-1", &ids[1]).is_some());
+    }
+}
@@ -1,92 +1,275 @@
-use std::{sync::Arc, time::Duration};
+use std::{path::Path, sync::Arc, time::Duration};
 
-use axum::{Router, routing::get};
+use futures::StreamExt;
 use tokio::{signal, sync::Mutex};
 use tracing::{error, info};
 
+mod api;
 mod firecracker;
+mod health;
+mod jobs;
 mod network;
+mod pool;
 mod vm;
 mod vm_handle;
 mod vm_store;
 mod vsock;
 
+const SIGINT: i32 = 2;
+
+/// Boots two VMs next to the API and walks them through the guest's features
+const DEMO_FLAG: &str = "--demo";
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt().init();
 
     network::setup_ip_forwarding().expect("Failed to setup forwarding");
 
-    let store = Arc::new(Mutex::new(vm_store::VmStore::new()));
-
-    let vm1 = vm::spawn_vm(store.lock().await.len() + 1);
-    let id1 = vm1.id.clone();
-
-    store.lock().await.add_vm(&id1, vm1);
+    let heartbeat = health::HeartbeatConfig::from_env().expect("Invalid heartbeat configuration");
 
-    let vm2 = vm::spawn_vm(store.lock().await.len() + 1);
-    let id2 = vm2.id.clone();
+    let pool_config = pool::PoolConfig::from_env().expect("Invalid pool configuration");
+    let pool_size = pool_config.size;
 
-    store.lock().await.add_vm(&id2, vm2);
+    let store = Arc::new(Mutex::new(vm_store::VmStore::new(pool_config)));
 
-    let vm1 = store.lock().await.get_vm(&id1).unwrap();
-    let vm2 = store.lock().await.get_vm(&id2).unwrap();
+    let refill = (pool_size > 0).then(|| {
+        info!("Keeping {} idle VMs per template", pool_size);
+        tokio::spawn(pool::keep_filled(store.clone(), heartbeat))
+    });
 
-    let handles: Vec<_> = [vm1, vm2]
-        .into_iter()
-        .map(|vm| tokio::spawn(handle_vm(vm)))
-        .collect();
+    if std::env::args().any(|arg| arg == DEMO_FLAG) {
+        run_demo(store.clone(), heartbeat).await;
+    }
 
-    let app = Router::new().route("/", get(|| async { "Hello, World!" }));
+    let app = api::router(api::AppState {
+        store: store.clone(),
+        heartbeat,
+        jobs: Arc::new(Mutex::new(jobs::JobStore::default())),
+    });
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
 
     info!("Starting axum");
 
-    tokio::select! {
-        result = axum::serve(listener, app).with_graceful_shutdown(shutdown_signal()) => {
-            if let Err(e) = result {
-                error!("Axum error: {}", e);
-            }
-            info!("Axum shut down, stopping VMs...");
-        }
+    let result = axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await;
 
-        result = futures::future::join_all(handles) => {
-            result.into_iter().filter_map(|r| r.err())
-            .for_each(|e| error!("Error from task: {}", e));
-        }
+    if let Err(e) = result {
+        error!("Axum error: {}", e);
     }
 
+    info!("Axum shut down, stopping VMs...");
+
     info!("Stopping orchestrator, removing VM's");
 
-    store.lock().await.remove_vm(&id1);
-    store.lock().await.remove_vm(&id2);
+    // Stopped first so it does not boot replacements for the VMs removed here
+    if let Some(refill) = refill {
+        refill.abort();
+    }
+
+    let vms = store.lock().await.remove_all();
+
+    for vm in vms {
+        // Removed VMs may finish their own shutdown meanwhile
+        if let Err(e) = vm.shutdown().await
+            && !vm.is_finished()
+        {
+            error!("Error shutting down {}: {}", vm.id, e);
+        }
+    }
 
     network::cleanup_ip_forwarding().expect("Failed to cleanup forwarding");
 }
 
+async fn run_demo(store: Arc<Mutex<vm_store::VmStore>>, heartbeat: health::HeartbeatConfig) {
+    let mut demo_vms = Vec::new();
+
+    for _ in 0..2 {
+        let vm = store
+            .lock()
+            .await
+            .create_vm(vm::VmResources::default(), heartbeat)
+            .expect("Failed to create VM");
+
+        demo_vms.push(vm);
+    }
+
+    let handles: Vec<_> = demo_vms
+        .into_iter()
+        .map(|vm| {
+            let store = store.clone();
+
+            tokio::spawn(async move {
+                let id = vm.id.clone();
+                handle_vm(vm).await;
+                store.lock().await.remove_vm(&id);
+            })
+        })
+        .collect();
+
+    // The demo runs next to the API, which keeps serving once it is done
+    tokio::spawn(async move {
+        futures::future::join_all(handles)
+            .await
+            .into_iter()
+            .filter_map(|r| r.err())
+            .for_each(|e| error!("Error from task: {}", e));
+    });
+}
+
 async fn handle_vm(vm: Arc<vm_handle::VmHandle>) {
     vm.start_vm().await.unwrap();
 
+    let uname_cmd = protocol::RunCommand {
+        command: "uname".to_string(),
+        args: vec!["-a".to_string()],
+        timeout_ms: Some(5_000),
+        ..Default::default()
+    };
+
+    let output = vm.send_command(uname_cmd).await.unwrap();
+
+    info!(
+        "Command on {} exited with {:?} after {} ms:\n{}",
+        vm.id,
+        output.exit_code,
+        output.duration_ms,
+        String::from_utf8_lossy(&output.stdout)
+    );
+
     let curl_cmd = protocol::RunCommand {
         command: "curl".to_string(),
         args: vec!["-v".to_string(), "http://example.com".to_string()],
-        env: std::collections::HashMap::new(),
-        working_dir: None,
+        timeout_ms: Some(30_000),
+        ..Default::default()
+    };
+
+    let mut output = vm.stream_command(curl_cmd).await.unwrap();
+
+    while let Some(msg) = output.next().await {
+        match msg {
+            protocol::Message::OutputChunk(chunk) => info!(
+                "[{} {:?} #{}] {}",
+                vm.id,
+                chunk.stream,
+                chunk.seq,
+                String::from_utf8_lossy(&chunk.data)
+            ),
+            protocol::Message::ProcessExit(exit) => info!(
+                "Command on {} exited with {:?} after {} ms (timed out: {})",
+                vm.id, exit.exit_code, exit.duration_ms, exit.timed_out
+            ),
+            protocol::Message::Error { kind, message, .. } => {
+                error!("Command on {} failed ({:?}): {}", vm.id, kind, message)
+            }
+            m => info!("Unexpected message from {}: {:?}", vm.id, m),
+        }
+    }
+
+    let sleep_cmd = protocol::RunCommand {
+        command: "sleep".to_string(),
+        args: vec!["60".to_string()],
+        ..Default::default()
+    };
+
+    // Interrupt one sleeper and cancel the other
+    let interrupted = vm.submit_command(sleep_cmd.clone()).await.unwrap();
+    let cancelled = vm.submit_command(sleep_cmd).await.unwrap();
+
+    vm.signal(interrupted.request_id, SIGINT).await.unwrap();
+    vm.cancel(cancelled.request_id).await.unwrap();
+
+    for sleeper in [interrupted, cancelled] {
+        let output = sleeper.output().await.unwrap();
+
+        info!(
+            "Sleeper on {} stopped by signal {:?} (cancelled: {})",
+            vm.id, output.signal, output.cancelled
+        );
+    }
+
+    // Feed stdin in pieces
+    let wc_cmd = protocol::RunCommand {
+        command: "wc".to_string(),
+        args: vec!["-c".to_string()],
+        stdin: Some(b"hello".to_vec()),
+        keep_stdin_open: true,
+        ..Default::default()
     };
 
-    vm.send_command(curl_cmd).await.unwrap();
+    let counter = vm.submit_command(wc_cmd).await.unwrap();
+
+    vm.send_stdin(counter.request_id, b" world".to_vec())
+        .await
+        .unwrap();
+    vm.close_stdin(counter.request_id).await.unwrap();
+
+    let output = counter.output().await.unwrap();
 
-    protocol::tar::tar_workspace("workspace", "workspace.tar").expect("Failed to create tarball");
+    info!(
+        "Byte count on {}: {}",
+        vm.id,
+        String::from_utf8_lossy(&output.stdout).trim()
+    );
 
-    let data = std::fs::read("workspace.tar").expect("Failed to read tarball");
+    let compression = protocol::tar::Compression::Zstd;
+
+    protocol::tar::tar_workspace("workspace", "workspace.tar.zst", compression)
+        .expect("Failed to create tarball");
+
+    let upload = vm
+        .upload_file(
+            Path::new("workspace.tar.zst"),
+            "/tmp/uploads/workspace.tar.zst",
+        )
+        .await
+        .expect("Failed to upload tarball");
+
+    info!(
+        "Uploaded {} bytes to {} on {}",
+        upload.size, upload.path, vm.id
+    );
 
     let ws_cmd = protocol::WorkspaceRunOptions {
-        data,
+        source: protocol::WorkspaceSource::Archive(upload.path),
         entrypoint: "run.sh".to_string(),
+        stream_output: false,
+        timeout_ms: Some(60_000),
+        artifacts: vec!["*.txt".to_string()],
+        compression,
     };
 
-    vm.send_workspace_command(ws_cmd).await.unwrap();
+    let output = vm.send_workspace_command(ws_cmd).await.unwrap();
+
+    info!(
+        "Workspace on {} exited with {:?} after {} ms:\n{}",
+        vm.id,
+        output.exit_code,
+        output.duration_ms,
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    if let Some(archive) = output.artifacts {
+        let local = format!("{}-artifacts.tar", vm.id);
+
+        match vm.fetch_file(&archive, Path::new(&local)).await {
+            Ok(info) => info!("Saved {} bytes of artifacts to {}", info.size, local),
+            Err(e) => error!("Failed to fetch artifacts from {}: {}", vm.id, e),
+        }
+    }
+
+    // The workspace itself is gone once the run ends, its artifacts stay
+    let local = format!("{}-artifacts-dir.tar", vm.id);
+
+    match vm.fetch_dir("/tmp/artifacts", Path::new(&local)).await {
+        Ok(info) => info!(
+            "Saved artifacts directory of {} bytes to {}",
+            info.size, local
+        ),
+        Err(e) => error!("Failed to fetch artifacts directory from {}: {}", vm.id, e),
+    }
 
     tokio::time::sleep(Duration::from_secs(5)).await;
 
@@ -0,0 +1,149 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+use crate::{health::HeartbeatConfig, vm::VmResources, vm_store::VmStore};
+
+/// Idle VMs to keep booted per template. 0 turns the pool off.
+const SIZE_ENV: &str = "SECEX_POOL_SIZE";
+
+/// Comma separated templates as `<vcpus>x<MiB>`, e.g. `1x512,2x1024`
+const TEMPLATES_ENV: &str = "SECEX_POOL_TEMPLATES";
+
+/// What to do with a pooled VM after a one-shot run: `recycle` or `destroy`
+const AFTER_USE_ENV: &str = "SECEX_POOL_AFTER_USE";
+
+/// How often idle VMs are checked for health when no take wakes the refill
+/// task earlier
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AfterUse {
+    /// Puts the VM back in the pool if it is healthy and the pool has room.
+    /// Its processes are killed and its workspaces and artifacts removed
+    /// first, but anything a run wrote elsewhere on the guest is still there
+    /// for the next one.
+    Recycle,
+    /// Destroys the VM, so no run ever sees what an earlier one left behind
+    Destroy,
+}
+
+impl FromStr for AfterUse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "recycle" => Ok(AfterUse::Recycle),
+            "destroy" => Ok(AfterUse::Destroy),
+            other => Err(format!("Unknown after-use policy: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub size: usize,
+    pub templates: Vec<VmResources>,
+    pub after_use: AfterUse,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 0,
+            templates: vec![VmResources::default()],
+            after_use: AfterUse::Destroy,
+        }
+    }
+}
+
+impl PoolConfig {
+    /// The defaults, overridden by whichever pool variables are set in the
+    /// environment
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = PoolConfig::default();
+
+        if let Ok(size) = std::env::var(SIZE_ENV) {
+            config.size = size
+                .parse()
+                .map_err(|e| format!("Invalid {}: {}", SIZE_ENV, e))?;
+        }
+
+        if let Ok(templates) = std::env::var(TEMPLATES_ENV) {
+            config.templates = templates
+                .split(',')
+                .map(parse_template)
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("Invalid {}: {}", TEMPLATES_ENV, e))?;
+        }
+
+        if let Ok(policy) = std::env::var(AFTER_USE_ENV) {
+            config.after_use = policy.parse()?;
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_template(s: &str) -> Result<VmResources, String> {
+    let (vcpus, mem) = s
+        .trim()
+        .split_once('x')
+        .ok_or_else(|| format!("{} is not <vcpus>x<MiB>", s))?;
+
+    Ok(VmResources {
+        vcpu_count: vcpus.parse().map_err(|e| format!("{}: {}", s, e))?,
+        mem_size_mib: mem.parse().map_err(|e| format!("{}: {}", s, e))?,
+    })
+}
+
+/// Keeps the pool topped up: replaces idle VMs that turned unhealthy and
+/// boots new ones whenever a take or a failed boot left a template short
+pub async fn keep_filled(store: Arc<Mutex<VmStore>>, heartbeat: HeartbeatConfig) {
+    let wake = store.lock().await.pool_wake();
+
+    loop {
+        let (evicted, warming) = {
+            let mut store = store.lock().await;
+            (store.evict_unhealthy(), store.refill_pool(heartbeat))
+        };
+
+        for vm in evicted {
+            warn!("Evicting unhealthy {} from the pool", vm.id);
+
+            tokio::spawn(async move {
+                if let Err(e) = vm.shutdown().await {
+                    error!("Failed to tear down {}: {}", vm.id, e);
+                }
+            });
+        }
+
+        for vm in warming {
+            let store = store.clone();
+
+            tokio::spawn(async move {
+                let booted = match vm.boot().await {
+                    Ok(_) => true,
+                    Err(e) => {
+                        error!("Failed to boot {} for the pool: {}", vm.id, e);
+                        false
+                    }
+                };
+
+                let failed = store.lock().await.pool_ready(&vm.id, booted);
+
+                if let Some(vm) = failed
+                    && let Err(e) = vm.shutdown().await
+                {
+                    error!("Failed to tear down {}: {}", vm.id, e);
+                }
+            });
+        }
+
+        tokio::select! {
+            _ = wake.notified() => {},
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {},
+        }
+    }
+}
@@ -1,30 +1,96 @@
 use std::{
+    collections::{HashMap, VecDeque},
     fs::{self, File},
     net::Ipv4Addr,
     path::{Path, PathBuf},
     process::{Command, Stdio},
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::Duration,
 };
 
 use macaddr::{MacAddr, MacAddr6};
-use tokio::{io::AsyncReadExt, net::unix::OwnedWriteHalf, process::Child};
-use tracing::{error, info};
+use tokio::{
+    io::AsyncReadExt,
+    net::unix::{OwnedReadHalf, OwnedWriteHalf},
+    process::Child,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        watch,
+    },
+    task::JoinHandle,
+    time::MissedTickBehavior,
+};
+use tracing::{error, info, warn};
 
 use crate::{
-    firecracker, network,
-    vm_handle::{VmHandle, VmMessage},
+    firecracker,
+    health::{Health, HeartbeatConfig, RecoveryAction},
+    network,
+    vm_handle::{Responder, VmHandle, VmMessage},
     vsock,
 };
 
-pub fn spawn_vm(seq: usize) -> VmHandle {
-    let vm = VmActor::new(seq);
+/// Number of requests a guest executes at once
+const GUEST_MAX_CONCURRENCY: usize = 8;
+
+/// Largest frame body exchanged with a guest, in either direction
+const MAX_FRAME_SIZE: usize = protocol::DEFAULT_MAX_FRAME_SIZE;
+
+/// Announced to guests in `Hello`
+const CAPABILITIES: &[protocol::Capability] = &[
+    protocol::Capability::Streaming,
+    protocol::Capability::Pty,
+    protocol::Capability::FileOps,
+    protocol::Capability::FileTransfer,
+    protocol::Capability::Compression,
+    protocol::Capability::Heartbeat,
+];
+
+/// Time Firecracker gets to create the vsock UDS after it is spawned
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time the guest gets to accept the vsock connection once the UDS exists
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Time a freshly connected guest gets to answer `Hello`
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request ID of the `Hello` exchange. Handles allocate IDs from 1.
+const HANDSHAKE_REQUEST_ID: u64 = 0;
+
+/// Request ID of `Ping`s. Their `Pong`s are handled by the actor itself.
+const HEARTBEAT_REQUEST_ID: u64 = 0;
+
+/// Machine size of a VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VmResources {
+    pub vcpu_count: u32,
+    pub mem_size_mib: u32,
+}
+
+impl Default for VmResources {
+    fn default() -> Self {
+        VmResources {
+            vcpu_count: 1,
+            mem_size_mib: 512,
+        }
+    }
+}
+
+pub fn spawn_vm(seq: usize, resources: VmResources, heartbeat: HeartbeatConfig) -> VmHandle {
+    let (health_tx, health_rx) = watch::channel(Health::Starting);
+
+    let vm = VmActor::new(seq, resources, heartbeat, health_tx);
     let id = vm.id.clone();
 
-    let (tx, rx) = tokio::sync::mpsc::channel(32);
+    let (tx, rx) = mpsc::channel(32);
 
     tokio::spawn(vm.run(rx));
 
-    VmHandle::new(id, tx)
+    VmHandle::new(id, seq, resources, tx, health_rx)
 }
 
 pub struct VmActor {
@@ -35,13 +101,31 @@ pub struct VmActor {
     guest_ip: Ipv4Addr,
     guest_cid: u32,
     mac: MacAddr,
+    resources: VmResources,
     process: Mutex<Option<Child>>,
     writer: tokio::sync::Mutex<Option<OwnedWriteHalf>>,
     vsock_path: String,
+    pending: Mutex<HashMap<u64, Responder>>,
+    /// Envelope version agreed on with the guest
+    version: AtomicU32,
+    /// What the guest announced in its `Hello`
+    capabilities: Mutex<Vec<protocol::Capability>>,
+    /// Task reading the guest's messages
+    incoming: Mutex<Option<JoinHandle<()>>>,
+    heartbeat: HeartbeatConfig,
+    health: watch::Sender<Health>,
+    /// Pings sent since the last `Pong`
+    missed_pings: AtomicU32,
+    next_nonce: AtomicU64,
 }
 
 impl VmActor {
-    fn new(seq: usize) -> Self {
+    fn new(
+        seq: usize,
+        resources: VmResources,
+        heartbeat: HeartbeatConfig,
+        health: watch::Sender<Health>,
+    ) -> Self {
         let id = format!("vm-{}", seq);
         let socket_name = format!("/tmp/firecracker-{}.sock", seq);
         let vsock_path = format!("/tmp/vsock-{}.sock", id);
@@ -62,32 +146,48 @@ impl VmActor {
             guest_ip,
             guest_cid,
             mac,
+            resources,
             process: Mutex::new(None),
             writer: tokio::sync::Mutex::new(None),
             vsock_path,
+            pending: Mutex::new(HashMap::new()),
+            version: AtomicU32::new(protocol::MIN_VERSION),
+            capabilities: Mutex::new(Vec::new()),
+            incoming: Mutex::new(None),
+            heartbeat,
+            health,
+            missed_pings: AtomicU32::new(0),
+            next_nonce: AtomicU64::new(0),
         }
     }
 
-    pub async fn launch(self: Arc<Self>) {
-        self.edit_vm_config();
+    /// Boots the VM and connects to the guest. On failure whatever was set
+    /// up so far is left for `destroy` to tear down.
+    pub async fn launch(self: Arc<Self>) -> Result<(), String> {
+        self.health.send_replace(Health::Starting);
+
+        self.edit_vm_config()
+            .map_err(|e| format!("Failed to write Firecracker config: {}", e))?;
         self.remove_existing_socket();
 
-        vsock::remove_existing_vsock(&self.vsock_path);
+        vsock::remove_existing_vsock(&self.vsock_path)?;
 
         network::setup_tap_device(&self.tap, &self.host_ip.to_string(), "/30")
-            .expect("Tap setup failed");
+            .map_err(|e| format!("Tap setup failed: {}", e))?;
 
-        let current_dir = std::env::current_dir().expect("Failed to get current directory");
+        let current_dir = std::env::current_dir()
+            .map_err(|e| format!("Failed to get current directory: {}", e))?;
         let firecracker_path = current_dir.join("firecracker");
 
         info!("Current dir: {:?}", current_dir);
 
-        self.create_rootfs_file().expect("Failed to create rootfs");
+        self.create_rootfs_file()
+            .map_err(|e| format!("Failed to create rootfs: {}", e))?;
 
-        let stdout_file =
-            File::create(format!("{}.out.log", self.id)).expect("Failed to create stdout log file");
-        let stderr_file =
-            File::create(format!("{}.err.log", self.id)).expect("Failed to create stderr log file");
+        let stdout_file = File::create(format!("{}.out.log", self.id))
+            .map_err(|e| format!("Failed to create stdout log file: {}", e))?;
+        let stderr_file = File::create(format!("{}.err.log", self.id))
+            .map_err(|e| format!("Failed to create stderr log file: {}", e))?;
 
         let child = tokio::process::Command::new(&firecracker_path)
             .arg("--api-sock")
@@ -97,8 +197,10 @@ impl VmActor {
             .arg(current_dir.join(self.config_name()))
             .stdout(Stdio::from(stdout_file))
             .stderr(Stdio::from(stderr_file))
+            // Killed with its `Child`, even if the actor goes without a shutdown
+            .kill_on_drop(true)
             .spawn()
-            .expect("Failed to start firecracker");
+            .map_err(|e| format!("Failed to start firecracker: {}", e))?;
 
         {
             let mut process = self.process.lock().expect("Failed to grab process mutex");
@@ -110,74 +212,474 @@ impl VmActor {
             self.id, self.api_socket, self.tap
         );
 
-        vsock::wait_for_socket(&self.vsock_path).await;
+        vsock::wait_for_socket(&self.vsock_path, SOCKET_TIMEOUT).await?;
 
-        let stream = vsock::connect_to_vsock(&self.vsock_path).await;
+        let stream = vsock::connect_to_vsock(&self.vsock_path, CONNECT_TIMEOUT).await?;
 
-        let (reader, writer) = stream.into_split();
+        let (mut reader, writer) = stream.into_split();
 
         {
             let mut write_guard = self.writer.lock().await;
             *write_guard = Some(writer);
         }
 
-        tokio::spawn(async move { self.handle_incoming(reader).await });
+        self.handshake(&mut reader)
+            .await
+            .map_err(|e| format!("Handshake failed: {}", e))?;
+
+        self.health.send_replace(Health::Healthy);
+
+        let actor = self.clone();
+        let incoming = tokio::spawn(async move { actor.handle_incoming(reader).await });
+
+        *self.incoming.lock().expect("Failed to grab incoming mutex") = Some(incoming);
+
+        Ok(())
+    }
+
+    /// Launches the VM while still taking messages, so that a shutdown does
+    /// not wait for a boot that may never finish. Returns the messages that
+    /// came in meanwhile. Requests among them wait for the launch, a
+    /// shutdown cancels it.
+    async fn start(self: &Arc<Self>, rx: &mut mpsc::Receiver<VmMessage>) -> Vec<VmMessage> {
+        let launch = self.clone().launch();
+        tokio::pin!(launch);
+
+        let mut received = Vec::new();
+
+        loop {
+            tokio::select! {
+                result = &mut launch => {
+                    if let Err(e) = result {
+                        self.launch_failed(e).await;
+                    }
+
+                    return received;
+                }
+                Some(msg) = rx.recv() => {
+                    let shutdown = matches!(msg, VmMessage::Shutdown(_));
+                    received.push(msg);
+
+                    if shutdown {
+                        info!("{} shut down while launching", self.id);
+                        return received;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tears down what a failed launch left behind. The VM stays stopped.
+    async fn launch_failed(&self, e: String) {
+        error!("Failed to launch {}: {}", self.id, e);
+        self.destroy().await;
     }
 
-    pub async fn run(self, mut rx: tokio::sync::mpsc::Receiver<VmMessage>) {
+    pub async fn run(self, mut rx: mpsc::Receiver<VmMessage>) {
         let self_pointer = Arc::new(self);
 
-        while let Some(msg) = rx.recv().await {
+        // Recovery stops and relaunches the VM, so it runs here rather than
+        // in the monitor, in turn with launches and shutdowns
+        let (recover_tx, mut recover_rx) = mpsc::channel(1);
+
+        tokio::spawn(monitor(Arc::downgrade(&self_pointer), recover_tx));
+
+        let mut queue = VecDeque::new();
+
+        loop {
+            let msg = match queue.pop_front() {
+                Some(msg) => msg,
+                None => tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => return,
+                    },
+                    Some(()) = recover_rx.recv() => {
+                        queue.extend(self_pointer.recover(&mut rx).await);
+                        continue;
+                    }
+                },
+            };
+
             match msg {
-                VmMessage::StartVm => self_pointer.clone().launch().await,
-                VmMessage::Command(run_command) => self_pointer
-                    .send_message(protocol::Message::RunCommand(run_command))
-                    .await
-                    .unwrap(),
-                VmMessage::WorkspaceCommand(workspace_run_options) => self_pointer
-                    .send_message(protocol::Message::RunWorkspace(workspace_run_options))
-                    .await
-                    .unwrap(),
-                VmMessage::Shutdown => self_pointer.cleanup(),
+                VmMessage::StartVm => queue.extend(self_pointer.start(&mut rx).await),
+                VmMessage::Request(request_id, msg, reply) => {
+                    self_pointer.send_request(request_id, msg, reply).await
+                }
+                VmMessage::Shutdown(done) => {
+                    self_pointer.destroy().await;
+                    let _ = done.send(());
+                    return;
+                }
             }
         }
     }
 
-    async fn send_message(&self, msg: protocol::Message) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(stream) = self.writer.lock().await.as_mut() {
-            protocol::send_msg(stream, msg)
+    /// Exchanges `Hello`s with the guest and settles on the newest envelope
+    /// version both sides speak
+    async fn handshake(&self, reader: &mut OwnedReadHalf) -> Result<(), String> {
+        let hello = protocol::Hello::new(CAPABILITIES.to_vec());
+
+        self.send_message(
+            HANDSHAKE_REQUEST_ID,
+            protocol::Message::Hello(hello.clone()),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let options = self.wire_options();
+        let reply =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, protocol::recv_msg_with(reader, &options))
                 .await
-                .expect("Error sedning to stream");
+                .map_err(|_| "guest did not answer Hello".to_string())?
+                .map_err(|e| e.to_string())?;
+
+        let peer = match reply.message {
+            protocol::Message::Hello(peer) => peer,
+            m => return Err(format!("expected Hello, got {:?}", m)),
         };
 
+        let version = hello.negotiate(&peer).map_err(|e| e.to_string())?;
+
+        info!(
+            "Guest {} speaks versions {}..={}, using {} with {:?}",
+            self.id, peer.min_version, peer.max_version, version, peer.capabilities
+        );
+
+        self.version.store(version, Ordering::Relaxed);
+        *self
+            .capabilities
+            .lock()
+            .expect("Failed to grab capabilities mutex") = peer.capabilities;
+
         Ok(())
     }
 
+    /// Pings the guest. Returns `true` once too many pings in a row went
+    /// unanswered and the guest needs recovering. Guests that are not
+    /// connected or cannot answer pings are left alone.
+    async fn heartbeat(&self) -> bool {
+        if !matches!(*self.health.borrow(), Health::Healthy | Health::Unhealthy)
+            || !self
+                .capabilities
+                .lock()
+                .expect("Failed to grab capabilities mutex")
+                .contains(&protocol::Capability::Heartbeat)
+        {
+            return false;
+        }
+
+        let missed = self.missed_pings.fetch_add(1, Ordering::Relaxed);
+
+        if missed >= self.heartbeat.max_missed
+            && self.health.send_replace(Health::Unhealthy) == Health::Healthy
+        {
+            error!("{} missed {} pings, marking it unhealthy", self.id, missed);
+
+            return true;
+        }
+
+        let ping = protocol::Message::Ping {
+            nonce: self.next_nonce.fetch_add(1, Ordering::Relaxed),
+        };
+
+        // A hung guest stops reading, which can block the write for good
+        match tokio::time::timeout(
+            self.heartbeat.interval,
+            self.send_message(HEARTBEAT_REQUEST_ID, ping),
+        )
+        .await
+        {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("Error pinging {}: {}", self.id, e),
+            Err(_) => warn!("Timed out pinging {}", self.id),
+        }
+
+        false
+    }
+
+    fn pong_received(&self) {
+        self.missed_pings.store(0, Ordering::Relaxed);
+
+        let recovered = self.health.send_if_modified(|health| match health {
+            Health::Unhealthy => {
+                *health = Health::Healthy;
+                true
+            }
+            _ => false,
+        });
+
+        if recovered {
+            info!("{} is answering pings again", self.id);
+        }
+    }
+
+    /// Acts on a guest the heartbeat found unresponsive. Returns the
+    /// messages that came in during a restart, see `start`.
+    async fn recover(self: &Arc<Self>, rx: &mut mpsc::Receiver<VmMessage>) -> Vec<VmMessage> {
+        // It may have answered a ping since, or been stopped
+        if *self.health.borrow() != Health::Unhealthy {
+            return Vec::new();
+        }
+
+        match self.heartbeat.recovery {
+            RecoveryAction::Alert => {
+                error!("{} is unresponsive, leaving it running", self.id)
+            }
+            RecoveryAction::Destroy => {
+                info!("Destroying unresponsive {}", self.id);
+                self.stop().await;
+            }
+            RecoveryAction::Restart => {
+                info!("Restarting unresponsive {}", self.id);
+                self.stop().await;
+
+                return self.start(rx).await;
+            }
+        }
+
+        Vec::new()
+    }
+
+    /// Kills Firecracker and forgets the connection. Callers waiting for
+    /// replies see their requests fail.
+    async fn stop(&self) {
+        let child = self
+            .process
+            .lock()
+            .expect("Failed to grab process mutex")
+            .take();
+
+        // Killing the VM also unblocks a write stuck on the connection
+        if let Some(mut child) = child {
+            if let Err(e) = child.start_kill() {
+                warn!("Error killing {}: {}", self.id, e);
+            }
+
+            let _ = child.wait().await;
+        }
+
+        let incoming = self
+            .incoming
+            .lock()
+            .expect("Failed to grab incoming mutex")
+            .take();
+
+        // Stopped before it can clear the connection of a relaunched VM
+        if let Some(incoming) = incoming {
+            incoming.abort();
+            let _ = incoming.await;
+        }
+
+        self.writer.lock().await.take();
+        self.pending
+            .lock()
+            .expect("Failed to grab pending mutex")
+            .clear();
+        self.capabilities
+            .lock()
+            .expect("Failed to grab capabilities mutex")
+            .clear();
+        self.version.store(protocol::MIN_VERSION, Ordering::Relaxed);
+        self.missed_pings.store(0, Ordering::Relaxed);
+
+        if let Err(e) = network::cleanup_tap_device(&self.tap) {
+            error!("Error deleting tap of {}: {}", self.id, e);
+        }
+
+        self.health.send_replace(Health::Stopped);
+    }
+
+    fn wire_options(&self) -> protocol::WireOptions {
+        protocol::WireOptions {
+            max_frame_size: MAX_FRAME_SIZE,
+            max_version: self.version.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sends a request to the guest and registers `reply` to receive the
+    /// guest's answers. A request the guest lacks the capability for is
+    /// answered with an `Unsupported` error right away. If sending fails the
+    /// reply sender is dropped, which the waiting caller observes as a
+    /// closed channel.
+    async fn send_request(
+        &self,
+        request_id: u64,
+        msg: protocol::Message,
+        reply: Option<Responder>,
+    ) {
+        if let Some(needed) = msg.required_capability()
+            && !self
+                .capabilities
+                .lock()
+                .expect("Failed to grab capabilities mutex")
+                .contains(&needed)
+        {
+            error!(
+                "{} does not support {:?}, rejecting request {}",
+                self.id, needed, request_id
+            );
+
+            if let Some(reply) = reply {
+                let _ = reply.try_send(protocol::Message::Error {
+                    request_id,
+                    kind: protocol::ErrorKind::Unsupported,
+                    message: format!("{} does not support {:?}", self.id, needed),
+                });
+            }
+
+            return;
+        }
+
+        if let Some(reply) = reply {
+            self.pending
+                .lock()
+                .expect("Failed to grab pending mutex")
+                .insert(request_id, reply);
+        }
+
+        if let Err(e) = self.send_message(request_id, msg).await {
+            error!("Error sending request {} to {}: {}", request_id, self.id, e);
+
+            self.pending
+                .lock()
+                .expect("Failed to grab pending mutex")
+                .remove(&request_id);
+        }
+    }
+
+    async fn send_message(
+        &self,
+        request_id: u64,
+        msg: protocol::Message,
+    ) -> Result<(), protocol::ProtocolError> {
+        match self.writer.lock().await.as_mut() {
+            Some(stream) => {
+                protocol::send_msg_with(stream, request_id, msg, &self.wire_options()).await
+            }
+            // Never connected, or the connection was closed
+            None => Err(protocol::ProtocolError::Disconnected),
+        }
+    }
+
     async fn handle_incoming<T: AsyncReadExt + Unpin>(&self, mut stream: T) {
         loop {
-            let message = match protocol::recv_msg(&mut stream).await {
-                Ok(m) => m,
+            let envelope = match protocol::recv_msg_with(&mut stream, &self.wire_options()).await {
+                Ok(e) => e,
+                // The frame was read in full, so the connection is still usable
+                Err(
+                    e @ (protocol::ProtocolError::UnsupportedVersion { .. }
+                    | protocol::ProtocolError::Malformed(_)),
+                ) => {
+                    warn!("Skipping frame from {}: {}", self.id, e);
+                    continue;
+                }
                 Err(e) => {
-                    error!("Error receiving message: {}", e);
+                    match e {
+                        protocol::ProtocolError::Disconnected => {
+                            info!("Guest {} closed the connection", self.id)
+                        }
+                        e => error!("Closing connection to {}: {}", self.id, e),
+                    }
+
+                    // Dropping the write half closes the connection for the guest
+                    self.writer.lock().await.take();
+
+                    // Dropping the reply senders wakes up every waiting caller
+                    self.pending
+                        .lock()
+                        .expect("Failed to grab pending mutex")
+                        .clear();
+
                     return;
                 }
             };
 
-            match message {
-                protocol::Message::Hello => {
-                    info!("Guest said Hello!");
+            let reply = {
+                let mut pending = self.pending.lock().expect("Failed to grab pending mutex");
+
+                match envelope.message.is_final() {
+                    true => pending.remove(&envelope.request_id),
+                    false => pending.get(&envelope.request_id).cloned(),
+                }
+            };
+
+            if let Some(reply) = reply {
+                self.deliver(envelope.request_id, reply, envelope.message);
+                continue;
+            }
+
+            match envelope.message {
+                protocol::Message::Pong { .. } => self.pong_received(),
+                protocol::Message::Hello(_) => {
+                    info!("Guest said Hello again");
                 }
                 protocol::Message::CommandOutput(output) => {
-                    info!("Received command output from guest:",);
-                    info!("{}", output.output);
+                    info!(
+                        "Received command output from guest (exit code {:?}):",
+                        output.exit_code
+                    );
+                    info!("{}", String::from_utf8_lossy(&output.stdout));
                 }
                 m => info!("Received other message: {:?}", m),
             }
         }
     }
 
-    pub fn cleanup(&self) {
-        network::cleanup_tap_device(&self.tap).expect("Failed to delete tap");
+    /// Hands a reply to its caller without waiting, so that a caller that
+    /// stops reading cannot hold up the other requests' replies or the
+    /// heartbeat. Output chunks are dropped while the caller's buffer is
+    /// full. A caller without room for anything else is given up on.
+    fn deliver(&self, request_id: u64, reply: Responder, msg: protocol::Message) {
+        let full = !msg.is_final() && !reply.has_room(&msg);
+
+        let result = match msg {
+            protocol::Message::OutputChunk(chunk) if full => {
+                return warn!(
+                    "Caller for request {} is not keeping up, dropped output chunk {}",
+                    request_id, chunk.seq
+                );
+            }
+            _ if full => Err(TrySendError::Full(())),
+            msg => reply.try_send(msg),
+        };
+
+        match result {
+            Ok(_) => (),
+            Err(TrySendError::Closed(_)) => {
+                info!("Caller for request {} is no longer waiting", request_id)
+            }
+            Err(TrySendError::Full(_)) => {
+                warn!("Caller for request {} stalled, giving up on it", request_id);
+
+                // Dropping its reply sender ends the caller's stream
+                self.pending
+                    .lock()
+                    .expect("Failed to grab pending mutex")
+                    .remove(&request_id);
+            }
+        }
+    }
+
+    /// Stops the VM and removes the files and sockets it ran with. Logs are
+    /// kept.
+    async fn destroy(&self) {
+        self.stop().await;
+
+        for path in [self.rootfs_path(), self.config_name()] {
+            match fs::remove_file(&path) {
+                Ok(_) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => warn!("Error removing {}: {}", path, e),
+            }
+        }
+
+        if let Err(e) = vsock::remove_existing_vsock(&self.vsock_path) {
+            warn!("Error removing vsock UDS of {}: {}", self.id, e);
+        }
+        self.remove_existing_socket();
+
+        info!("{} destroyed", self.id);
     }
 
     fn create_rootfs_file(&self) -> Result<(), Box<dyn std::error::Error>> {
@@ -186,24 +688,24 @@ impl VmActor {
             info!("Created filesystems dir");
         }
 
-        fs::copy("build/rootfs.ext4", format!("filesystems/{}.ext4", self.id))?;
+        fs::copy("build/rootfs.ext4", self.rootfs_path())?;
         info!("Rootfs created ");
 
         Ok(())
     }
 
-    fn edit_vm_config(&self) {
-        let current_dir = std::env::current_dir().expect("Failed to get current directory");
+    fn edit_vm_config(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let current_dir = std::env::current_dir()?;
 
         let mut config = firecracker::FirecrackerConfig::from_file(
             &current_dir.join("crates/orchestrator/vm_config_template.json"),
-        )
-        .expect("Failed to read Firecracker config file");
+        )?;
 
         let boot_args = format!(
             "console=ttyS0 reboot=k panic=1 init=/init \
-            vm.ip={} vm.gateway={} vm.iface=eth0 vm.cid={}",
-            self.guest_ip, self.host_ip, self.guest_cid
+            vm.ip={} vm.gateway={} vm.iface=eth0 vm.cid={} \
+            vm.max_concurrency={} vm.max_frame_size={}",
+            self.guest_ip, self.host_ip, self.guest_cid, GUEST_MAX_CONCURRENCY, MAX_FRAME_SIZE
         );
 
         let log_path = current_dir.join(format!("{}-firecracker.log", self.id));
@@ -215,7 +717,7 @@ impl VmActor {
                 .to_str()
                 .expect("Invalid kernel path"),
             current_dir
-                .join(format!("filesystems/{}.ext4", self.id))
+                .join(self.rootfs_path())
                 .to_str()
                 .expect("Invalid rootfs path"),
             &self.tap,
@@ -228,13 +730,20 @@ impl VmActor {
             self.guest_cid,
         );
 
+        config.machine_config.vcpu_count = self.resources.vcpu_count.into();
+        config.machine_config.mem_size_mib = self.resources.mem_size_mib.into();
+
         let config_file = current_dir.join(self.config_name());
 
-        config
-            .to_file(&config_file)
-            .expect("Failed to write Firecracker config file");
+        config.to_file(&config_file)?;
 
         info!("Wrote Firecracker config to {}", config_file.display());
+
+        Ok(())
+    }
+
+    fn rootfs_path(&self) -> String {
+        format!("filesystems/{}.ext4", self.id)
     }
 
     fn config_name(&self) -> String {
@@ -252,3 +761,30 @@ impl VmActor {
         info!("Removed existing socket at {}", self.api_socket.display());
     }
 }
+
+/// Drives the heartbeat of an actor until the actor is gone
+/// Sends the heartbeat and asks the actor to recover its guest through
+/// `recover` when it stops answering
+async fn monitor(actor: Weak<VmActor>, recover: mpsc::Sender<()>) {
+    let Some(interval) = actor.upgrade().map(|a| a.heartbeat.interval) else {
+        return;
+    };
+
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+
+        let Some(actor) = actor.upgrade() else {
+            return;
+        };
+
+        // A full channel means a recovery is pending already
+        if actor.heartbeat().await
+            && let Err(TrySendError::Closed(_)) = recover.try_send(())
+        {
+            return;
+        }
+    }
+}
@@ -1,18 +1,196 @@
+use std::{
+    io::SeekFrom,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, watch,
+    },
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::warn;
+
+use crate::{health::Health, vm::VmResources};
+
+/// Receives every reply the guest sends for one request, up to and including
+/// the final one. Bounded in replies and in payload bytes, so a caller that
+/// stops reading cannot make the host buffer the guest's output without
+/// limit.
+#[derive(Clone)]
+pub struct Responder {
+    tx: mpsc::Sender<protocol::Message>,
+    /// Payload bytes sent and not yet taken by the caller
+    buffered: Arc<AtomicUsize>,
+}
+
+impl Responder {
+    /// Whether a reply that is not the final one fits, keeping a slot free
+    /// for the final reply
+    pub fn has_room(&self, msg: &protocol::Message) -> bool {
+        self.tx.capacity() > 1
+            && self.buffered.load(Ordering::Relaxed) + payload_len(msg) <= REPLY_BUFFER_BYTES
+    }
+
+    /// Queues a reply, or drops it if the caller has no room or is gone
+    pub fn try_send(&self, msg: protocol::Message) -> Result<(), TrySendError<()>> {
+        let len = payload_len(&msg);
+
+        // Counted first, the caller may take the reply right away
+        self.buffered.fetch_add(len, Ordering::Relaxed);
+
+        self.tx.try_send(msg).map_err(|e| {
+            self.buffered.fetch_sub(len, Ordering::Relaxed);
+
+            match e {
+                TrySendError::Full(_) => TrySendError::Full(()),
+                TrySendError::Closed(_) => TrySendError::Closed(()),
+            }
+        })
+    }
+}
+
+/// Replies buffered per request
+const REPLY_BUFFER: usize = 256;
+
+/// Payload bytes buffered per request, beyond which output chunks are
+/// dropped and other replies give up on the caller. The final reply is let
+/// through regardless, it is bounded by the frame size.
+const REPLY_BUFFER_BYTES: usize = 16 * 1024 * 1024;
+
+/// Upload chunks sent ahead of the guest's acknowledgements
+const UPLOAD_WINDOW: u64 = 8;
+
+/// Largest workspace tarball sent inline with its request. Larger ones are
+/// uploaded in chunks first, so that no frame has to hold them.
+const INLINE_ARCHIVE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Guest directory workspace tarballs are uploaded to
+const UPLOAD_DIR: &str = "/tmp/uploads";
+
+/// Time a VM gets to boot and answer `Hello`
+const BOOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Run on a guest before it is handed out again. `kill -1` reaches every
+/// process but init and the shell itself, so no run leaves anything
+/// running. Workspaces and artifacts are the files runs leave in `/tmp`.
+const RESET_SCRIPT: &str = "kill -KILL -1; rm -rf /tmp/workspace-* /tmp/artifacts /tmp/uploads";
+
+/// Time a guest gets to reset
+const RESET_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub enum VmMessage {
     StartVm,
-    Command(protocol::RunCommand),
-    WorkspaceCommand(protocol::WorkspaceRunOptions),
-    Shutdown,
+    /// A message for the guest. Replies are routed to the responder, if any.
+    Request(u64, protocol::Message, Option<Responder>),
+    /// Tears the VM down and stops the actor, then signals `done`
+    Shutdown(oneshot::Sender<()>),
 }
 
 pub struct VmHandle {
     pub id: String,
+    pub seq: usize,
+    pub resources: VmResources,
     tx: tokio::sync::mpsc::Sender<VmMessage>,
+    next_request_id: AtomicU64,
+    health: watch::Receiver<Health>,
+}
+
+/// Replies to a submitted command. Yields `OutputChunk`s for streamed runs
+/// and ends after the final reply.
+pub struct CommandStream {
+    pub request_id: u64,
+    replies: ReceiverStream<protocol::Message>,
+    buffered: Arc<AtomicUsize>,
+}
+
+impl CommandStream {
+    /// Waits for the `CommandOutput` of a non-streamed run
+    pub async fn output(mut self) -> Result<protocol::CommandOutput, Box<dyn std::error::Error>> {
+        match self.next().await {
+            Some(protocol::Message::CommandOutput(output)) => Ok(output),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+}
+
+impl Stream for CommandStream {
+    type Item = protocol::Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let reply = self.replies.poll_next_unpin(cx);
+
+        if let Poll::Ready(Some(msg)) = &reply {
+            self.buffered.fetch_sub(payload_len(msg), Ordering::Relaxed);
+        }
+
+        reply
+    }
 }
 
 impl VmHandle {
-    pub fn new(id: String, tx: tokio::sync::mpsc::Sender<VmMessage>) -> Self {
-        VmHandle { id, tx }
+    pub fn new(
+        id: String,
+        seq: usize,
+        resources: VmResources,
+        tx: tokio::sync::mpsc::Sender<VmMessage>,
+        health: watch::Receiver<Health>,
+    ) -> Self {
+        VmHandle {
+            id,
+            seq,
+            resources,
+            tx,
+            next_request_id: AtomicU64::new(1),
+            health,
+        }
+    }
+
+    /// Whether the actor has stopped, after a `shutdown` once the VM's host
+    /// resources are gone
+    pub fn is_finished(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Health as last seen by the heartbeat
+    pub fn health(&self) -> Health {
+        *self.health.borrow()
+    }
+
+    /// Waits until the guest has connected. Fails if it comes up unhealthy
+    /// or the VM stops.
+    pub async fn wait_ready(&self, timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+        let mut health = self.health.clone();
+
+        let health = tokio::time::timeout(timeout, health.wait_for(|h| *h != Health::Starting))
+            .await
+            .map_err(|_| "Timed out waiting for the VM to boot")?
+            .map(|h| *h)?;
+
+        match health {
+            Health::Healthy => Ok(()),
+            health => Err(format!("VM came up {:?}", health).into()),
+        }
+    }
+
+    pub fn info(&self) -> protocol::api::VmInfo {
+        protocol::api::VmInfo {
+            id: self.id.clone(),
+            health: self.health(),
+            vcpu_count: self.resources.vcpu_count,
+            mem_size_mib: self.resources.mem_size_mib,
+        }
     }
 
     pub async fn start_vm(&self) -> Result<(), Box<dyn std::error::Error>> {
@@ -21,27 +199,605 @@ impl VmHandle {
         Ok(())
     }
 
-    pub async fn send_command(
+    /// Starts the VM and waits until the guest is ready for requests
+    pub async fn boot(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.start_vm().await?;
+        self.wait_ready(BOOT_TIMEOUT).await
+    }
+
+    /// Sends a command to the guest without waiting for it to finish. The
+    /// returned stream carries the request ID needed to cancel or signal it.
+    pub async fn submit_command(
         &self,
         cmd: protocol::RunCommand,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.tx.send(VmMessage::Command(cmd)).await?;
+    ) -> Result<CommandStream, Box<dyn std::error::Error>> {
+        self.request(protocol::Message::RunCommand(cmd)).await
+    }
 
-        Ok(())
+    /// Sends a command to the guest and resolves once the guest has replied
+    /// with the output of that specific command.
+    pub async fn send_command(
+        &self,
+        mut cmd: protocol::RunCommand,
+    ) -> Result<protocol::CommandOutput, Box<dyn std::error::Error>> {
+        cmd.stream_output = false;
+
+        let replies = self.submit_command(cmd).await?;
+
+        replies.output().await
     }
 
-    pub async fn send_workspace_command(
+    /// Sends a command to the guest and returns its `OutputChunk`s as they
+    /// arrive. The stream ends after the `ProcessExit` message.
+    pub async fn stream_command(
+        &self,
+        mut cmd: protocol::RunCommand,
+    ) -> Result<CommandStream, Box<dyn std::error::Error>> {
+        cmd.stream_output = true;
+
+        self.submit_command(cmd).await
+    }
+
+    /// Sends a workspace run to the guest without waiting for it to finish
+    pub async fn submit_workspace(
         &self,
         cmd: protocol::WorkspaceRunOptions,
+    ) -> Result<CommandStream, Box<dyn std::error::Error>> {
+        self.request(protocol::Message::RunWorkspace(cmd)).await
+    }
+
+    pub async fn send_workspace_command(
+        &self,
+        mut cmd: protocol::WorkspaceRunOptions,
+    ) -> Result<protocol::CommandOutput, Box<dyn std::error::Error>> {
+        cmd.stream_output = false;
+
+        let replies = self.submit_workspace(cmd).await?;
+
+        replies.output().await
+    }
+
+    /// Where the guest finds a workspace tarball from the host's disk. Small
+    /// ones travel inline with the request. Larger ones are uploaded in
+    /// chunks, and `discard_upload` removes them once the run is over.
+    pub async fn workspace_source(
+        &self,
+        archive: &Path,
+    ) -> Result<protocol::WorkspaceSource, Box<dyn std::error::Error>> {
+        let size = tokio::fs::metadata(archive).await?.len();
+
+        if size <= INLINE_ARCHIVE_SIZE {
+            let data = tokio::fs::read(archive).await?;
+            return Ok(protocol::WorkspaceSource::Inline(data));
+        }
+
+        let remote = format!("{}/workspace-{}.tar", UPLOAD_DIR, uuid::Uuid::new_v4());
+        let upload = self.upload_file(archive, &remote).await?;
+
+        Ok(protocol::WorkspaceSource::Archive(upload.path))
+    }
+
+    /// Removes the tarball of a finished run from the guest, if
+    /// `workspace_source` uploaded one
+    pub async fn discard_upload(&self, source: Option<String>) {
+        let Some(path) = source else {
+            return;
+        };
+
+        if let Err(e) = self.remove(&path, false).await {
+            warn!("Error removing {} from {}: {}", path, self.id, e);
+        }
+    }
+
+    /// Runs a workspace tarball on the booted VM and reads back its
+    /// artifacts. Destroying or recycling the VM afterwards is up to the
+    /// caller.
+    pub async fn run_once(
+        &self,
+        archive: &Path,
+        request: protocol::api::WorkspaceRequest,
+    ) -> Result<protocol::api::RunResult, Box<dyn std::error::Error>> {
+        let source = self.workspace_source(archive).await?;
+        let uploaded = uploaded_path(&source);
+
+        let cmd = protocol::WorkspaceRunOptions {
+            source,
+            entrypoint: request.entrypoint,
+            stream_output: false,
+            timeout_ms: request.timeout_ms,
+            artifacts: request.artifacts,
+            compression: request.compression,
+        };
+
+        let output = self.send_workspace_command(cmd).await.map_err(sendable);
+
+        self.discard_upload(uploaded).await;
+
+        let output = output.map_err(|e| -> Box<dyn std::error::Error> { e })?;
+
+        // Removed from the guest so a recycled VM does not pile them up
+        let artifacts = match &output.artifacts {
+            Some(path) => {
+                let local = std::env::temp_dir()
+                    .join(format!("secex-artifacts-{}.tar", uuid::Uuid::new_v4()));
+
+                self.fetch_file(path, &local).await?;
+                let data = tokio::fs::read(&local).await;
+                let _ = tokio::fs::remove_file(&local).await;
+
+                self.remove(path, false).await?;
+
+                Some(data?)
+            }
+            None => None,
+        };
+
+        Ok(protocol::api::RunResult { output, artifacts })
+    }
+
+    /// Kills whatever earlier runs left running on the guest and removes
+    /// their workspaces and artifacts. Files written anywhere else stay.
+    pub async fn reset(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let cmd = protocol::RunCommand {
+            command: "/bin/sh".to_string(),
+            args: vec!["-c".to_string(), RESET_SCRIPT.to_string()],
+            ..Default::default()
+        };
+
+        let output = tokio::time::timeout(RESET_TIMEOUT, self.send_command(cmd))
+            .await
+            .map_err(|_| "Timed out resetting the guest")??;
+
+        match output.exit_code {
+            Some(0) => Ok(()),
+            code => Err(format!(
+                "Reset exited with {:?}: {}",
+                code,
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into()),
+        }
+    }
+
+    /// Stops a submitted request. Its final reply has `cancelled` set.
+    pub async fn cancel(&self, request_id: u64) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify(protocol::Message::Cancel { request_id }).await
+    }
+
+    /// Sends a signal to the process group of a submitted request
+    pub async fn signal(
+        &self,
+        request_id: u64,
+        signal: i32,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        self.tx.send(VmMessage::WorkspaceCommand(cmd)).await?;
+        self.notify(protocol::Message::Signal { request_id, signal })
+            .await
+    }
 
-        Ok(())
+    /// Writes to the stdin of a command submitted with `keep_stdin_open`
+    pub async fn send_stdin(
+        &self,
+        request_id: u64,
+        data: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify(protocol::Message::StdinChunk { request_id, data })
+            .await
+    }
+
+    pub async fn close_stdin(&self, request_id: u64) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify(protocol::Message::StdinClose { request_id })
+            .await
+    }
+
+    /// Starts an interactive session on a guest pty. The stream yields the
+    /// terminal output and ends with the session's `ProcessExit`.
+    pub async fn open_pty(
+        &self,
+        open: protocol::PtyOpen,
+    ) -> Result<CommandStream, Box<dyn std::error::Error>> {
+        self.request(protocol::Message::PtyOpen(open)).await
+    }
+
+    pub async fn pty_input(
+        &self,
+        request_id: u64,
+        data: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify(protocol::Message::PtyInput { request_id, data })
+            .await
+    }
+
+    pub async fn pty_resize(
+        &self,
+        request_id: u64,
+        size: protocol::TerminalSize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.notify(protocol::Message::PtyResize { request_id, size })
+            .await
     }
 
+    /// Copies a local file to `remote` on the guest in checksummed chunks,
+    /// reading it from disk as it goes. If an earlier upload of the same file
+    /// was interrupted, only the chunks the guest is missing are sent.
+    pub async fn upload_file(
+        &self,
+        local: &Path,
+        remote: &str,
+    ) -> Result<protocol::UploadComplete, Box<dyn std::error::Error>> {
+        let mut file = tokio::fs::File::open(local).await?;
+        let meta = file.metadata().await?;
+
+        let chunk_size = protocol::transfer::DEFAULT_CHUNK_SIZE as u64;
+        let begin = protocol::UploadBegin {
+            path: remote.to_string(),
+            size: meta.len(),
+            mode: meta.permissions().mode() & 0o7777,
+            sha256: protocol::transfer::sha256_file(local).await?,
+            chunk_size,
+        };
+
+        let mut replies = self.request(protocol::Message::UploadBegin(begin)).await?;
+        let request_id = replies.request_id;
+
+        let mut seq = match replies.next().await {
+            Some(protocol::Message::UploadReady { next_seq }) => next_seq,
+            reply => return Err(transfer_error(reply)),
+        };
+
+        file.seek(SeekFrom::Start(seq * chunk_size)).await?;
+
+        let mut in_flight = 0;
+
+        loop {
+            let mut data = Vec::with_capacity(chunk_size as usize);
+            (&mut file).take(chunk_size).read_to_end(&mut data).await?;
+
+            if data.is_empty() {
+                break;
+            }
+
+            if in_flight == UPLOAD_WINDOW {
+                wait_for_ack(&mut replies).await?;
+                in_flight -= 1;
+            }
+
+            self.notify(protocol::Message::UploadChunk {
+                request_id,
+                seq,
+                data,
+            })
+            .await?;
+
+            seq += 1;
+            in_flight += 1;
+        }
+
+        for _ in 0..in_flight {
+            wait_for_ack(&mut replies).await?;
+        }
+
+        self.notify(protocol::Message::UploadEnd { request_id })
+            .await?;
+
+        match replies.next().await {
+            Some(protocol::Message::UploadComplete(complete)) => Ok(complete),
+            reply => Err(transfer_error(reply)),
+        }
+    }
+
+    /// Copies the file at `remote` on the guest to `local`
+    pub async fn fetch_file(
+        &self,
+        remote: &str,
+        local: &Path,
+    ) -> Result<protocol::FileInfo, Box<dyn std::error::Error>> {
+        let path = remote.to_string();
+
+        self.download(protocol::Message::FetchFile { path }, local)
+            .await
+    }
+
+    /// Saves a tarball of the directory at `remote` on the guest to `local`
+    pub async fn fetch_dir(
+        &self,
+        remote: &str,
+        local: &Path,
+    ) -> Result<protocol::FileInfo, Box<dyn std::error::Error>> {
+        let path = remote.to_string();
+
+        self.download(protocol::Message::FetchDir { path }, local)
+            .await
+    }
+
+    pub async fn list_dir(
+        &self,
+        path: &str,
+    ) -> Result<Vec<protocol::fs::DirEntry>, Box<dyn std::error::Error>> {
+        let path = path.to_string();
+
+        match self.fs(protocol::fs::FsRequest::ListDir { path }).await? {
+            protocol::Message::DirListing(entries) => Ok(entries),
+            m => Err(unexpected_reply(Some(m))),
+        }
+    }
+
+    pub async fn stat(
+        &self,
+        path: &str,
+    ) -> Result<protocol::fs::FileStat, Box<dyn std::error::Error>> {
+        let path = path.to_string();
+
+        match self.fs(protocol::fs::FsRequest::Stat { path }).await? {
+            protocol::Message::FileStat(stat) => Ok(stat),
+            m => Err(unexpected_reply(Some(m))),
+        }
+    }
+
+    /// Reads up to `length` bytes from `offset`. Returns the bytes and the
+    /// size of the whole file.
+    pub async fn read_file(
+        &self,
+        path: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<(Vec<u8>, u64), Box<dyn std::error::Error>> {
+        let request = protocol::fs::FsRequest::ReadFile {
+            path: path.to_string(),
+            offset,
+            length,
+        };
+
+        match self.fs(request).await? {
+            protocol::Message::FileData { data, size } => Ok((data, size)),
+            m => Err(unexpected_reply(Some(m))),
+        }
+    }
+
+    pub async fn write_file(
+        &self,
+        path: &str,
+        data: Vec<u8>,
+        append: bool,
+        mode: Option<u32>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let request = protocol::fs::FsRequest::WriteFile {
+            path: path.to_string(),
+            data,
+            append,
+            mode,
+        };
+
+        self.fs_done(request).await
+    }
+
+    pub async fn create_dir(
+        &self,
+        path: &str,
+        recursive: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let path = path.to_string();
+
+        self.fs_done(protocol::fs::FsRequest::CreateDir { path, recursive })
+            .await
+    }
+
+    pub async fn remove(
+        &self,
+        path: &str,
+        recursive: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let path = path.to_string();
+
+        self.fs_done(protocol::fs::FsRequest::Remove { path, recursive })
+            .await
+    }
+
+    pub async fn rename(&self, from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let request = protocol::fs::FsRequest::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+
+        self.fs_done(request).await
+    }
+
+    /// Kills the VM and removes what it left on the host. Returns once the
+    /// teardown is done, after which the handle accepts no more requests.
     pub async fn shutdown(&self) -> Result<(), Box<dyn std::error::Error>> {
-        self.tx.send(VmMessage::Shutdown).await?;
+        let (done_tx, done_rx) = oneshot::channel();
+
+        self.tx.send(VmMessage::Shutdown(done_tx)).await?;
+        done_rx.await?;
 
         Ok(())
     }
+
+    async fn request(
+        &self,
+        msg: protocol::Message,
+    ) -> Result<CommandStream, Box<dyn std::error::Error>> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (reply_tx, reply_rx) = mpsc::channel(REPLY_BUFFER);
+        let buffered = Arc::new(AtomicUsize::new(0));
+
+        let responder = Responder {
+            tx: reply_tx,
+            buffered: buffered.clone(),
+        };
+
+        self.tx
+            .send(VmMessage::Request(request_id, msg, Some(responder)))
+            .await?;
+
+        Ok(CommandStream {
+            request_id,
+            replies: ReceiverStream::new(reply_rx),
+            buffered,
+        })
+    }
+
+    /// Writes a download to a partial file next to `local` and moves it into
+    /// place once its checksum matches. The partial file is removed if the
+    /// download fails.
+    async fn download(
+        &self,
+        msg: protocol::Message,
+        local: &Path,
+    ) -> Result<protocol::FileInfo, Box<dyn std::error::Error>> {
+        let mut replies = self.request(msg).await?;
+
+        let info = match replies.next().await {
+            Some(protocol::Message::DownloadBegin(info)) => info,
+            reply => return Err(transfer_error(reply)),
+        };
+
+        // `out.tar` becomes `out.tar.part`, never a sibling like `out.part`
+        let mut partial = local.as_os_str().to_owned();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+
+        if let Err(e) = receive_download(&mut replies, &info, &partial).await {
+            // Synchronous, as the boxed error cannot be held across an await
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+
+        tokio::fs::rename(&partial, local).await?;
+
+        Ok(info)
+    }
+
+    /// Runs a filesystem request on the guest. A failure comes back as a
+    /// `protocol::fs::FsError`, which callers can downcast to.
+    async fn fs(
+        &self,
+        request: protocol::fs::FsRequest,
+    ) -> Result<protocol::Message, Box<dyn std::error::Error>> {
+        let mut replies = self.request(protocol::Message::Fs(request)).await?;
+
+        match replies.next().await {
+            Some(protocol::Message::FsError(e)) => Err(e.into()),
+            Some(m) if !matches!(m, protocol::Message::Error { .. }) => Ok(m),
+            reply => Err(unexpected_reply(reply)),
+        }
+    }
+
+    async fn fs_done(
+        &self,
+        request: protocol::fs::FsRequest,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.fs(request).await? {
+            protocol::Message::FsDone => Ok(()),
+            m => Err(unexpected_reply(Some(m))),
+        }
+    }
+
+    /// Sends a message the guest does not reply to
+    async fn notify(&self, msg: protocol::Message) -> Result<(), Box<dyn std::error::Error>> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+
+        self.tx
+            .send(VmMessage::Request(request_id, msg, None))
+            .await?;
+
+        Ok(())
+    }
+}
+
+async fn wait_for_ack(replies: &mut CommandStream) -> Result<(), Box<dyn std::error::Error>> {
+    match replies.next().await {
+        Some(protocol::Message::UploadAck { .. }) => Ok(()),
+        reply => Err(transfer_error(reply)),
+    }
+}
+
+fn transfer_error(reply: Option<protocol::Message>) -> Box<dyn std::error::Error> {
+    match reply {
+        Some(protocol::Message::TransferFailed { reason }) => {
+            format!("Transfer failed: {}", reason).into()
+        }
+        reply => unexpected_reply(reply),
+    }
+}
+
+/// Writes the chunks of a download to `partial` and checks its checksum
+async fn receive_download(
+    replies: &mut CommandStream,
+    info: &protocol::FileInfo,
+    partial: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = tokio::fs::File::create(partial).await?;
+    let mut next_seq = 0;
+
+    loop {
+        match replies.next().await {
+            Some(protocol::Message::DownloadChunk { seq, data }) => {
+                if seq != next_seq {
+                    return Err(format!("Expected chunk {}, got {}", next_seq, seq).into());
+                }
+
+                file.write_all(&data).await?;
+                next_seq += 1;
+            }
+            Some(protocol::Message::DownloadEnd) => break,
+            reply => return Err(transfer_error(reply)),
+        }
+    }
+
+    file.sync_all().await?;
+    drop(file);
+
+    let sha256 = protocol::transfer::sha256_file(partial).await?;
+
+    if sha256 != info.sha256 {
+        return Err(format!(
+            "Checksum mismatch: expected {}, got {}",
+            info.sha256, sha256
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Error for a reply other than the one a request waits for. A guest
+/// `Error` becomes a `protocol::RequestError`, which callers can downcast to.
+fn unexpected_reply(reply: Option<protocol::Message>) -> Box<dyn std::error::Error> {
+    match reply {
+        Some(protocol::Message::Error { kind, message, .. }) => {
+            protocol::RequestError::new(kind, message).into()
+        }
+        Some(m) => format!("Unexpected reply from guest: {:?}", m).into(),
+        None => "VM closed the request without replying".into(),
+    }
+}
+
+/// Raw bytes a reply carries, which is what holds memory while it waits for
+/// its caller
+fn payload_len(msg: &protocol::Message) -> usize {
+    match msg {
+        protocol::Message::OutputChunk(chunk) => chunk.data.len(),
+        protocol::Message::CommandOutput(output) => output.stdout.len() + output.stderr.len(),
+        protocol::Message::DownloadChunk { data, .. }
+        | protocol::Message::FileData { data, .. } => data.len(),
+        _ => 0,
+    }
+}
+
+/// Path of a tarball `VmHandle::workspace_source` uploaded
+pub fn uploaded_path(source: &protocol::WorkspaceSource) -> Option<String> {
+    match source {
+        protocol::WorkspaceSource::Archive(path) => Some(path.clone()),
+        protocol::WorkspaceSource::Inline(_) => None,
+    }
+}
+
+/// Keeps a `RequestError`, which callers downcast to, and flattens anything
+/// else to its message, so that the error can be held across an await in a
+/// spawned task
+fn sendable(e: Box<dyn std::error::Error>) -> Box<dyn std::error::Error + Send + Sync> {
+    match e.downcast::<protocol::RequestError>() {
+        Ok(e) => e,
+        Err(e) => e.to_string().into(),
+    }
 }
@@ -1,31 +1,556 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
 
-use crate::vm_handle::VmHandle;
+use protocol::api::{PoolStats, TemplateStats};
+use tokio::sync::Notify;
 
+use crate::{
+    health::{Health, HeartbeatConfig},
+    pool::{AfterUse, PoolConfig},
+    vm::{self, VmResources},
+    vm_handle::VmHandle,
+};
+
+/// Highest VM sequence number. The sequence number ends up in the VM's IP
+/// addresses and MAC, which leave one byte for it.
+const MAX_SEQ: usize = 254;
+
+/// Pooled VMs of one template
+#[derive(Default)]
+struct TemplatePool {
+    /// Booted and handshaked, oldest first
+    idle: VecDeque<Arc<VmHandle>>,
+    /// Booting, moved to `idle` once ready
+    warming: HashMap<String, Arc<VmHandle>>,
+    /// IDs of VMs out on one-shot runs they may come back from. Their slots
+    /// are held, so no replacement boots for them meanwhile.
+    lent: HashSet<String>,
+    hits: u64,
+    misses: u64,
+}
+
+impl TemplatePool {
+    /// Slots in use, which the refill tops up to the pool size
+    fn slots(&self) -> usize {
+        self.idle.len() + self.warming.len() + self.lent.len()
+    }
+}
+
+/// Every VM the orchestrator runs. VMs handed out to requests are in `vms`,
+/// the pool's idle and booting VMs are kept apart until taken.
 pub struct VmStore {
     vms: HashMap<String, Arc<VmHandle>>,
+    pool_config: PoolConfig,
+    pool: HashMap<VmResources, TemplatePool>,
+    /// Wakes the refill task after a take
+    pool_wake: Arc<Notify>,
+    /// Removed VMs, which keep their sequence number until their actor has
+    /// finished tearing them down. Their TAP device, sockets and rootfs are
+    /// named after it.
+    retired: Vec<Arc<VmHandle>>,
 }
 
 impl VmStore {
-    pub fn new() -> Self {
+    pub fn new(pool_config: PoolConfig) -> Self {
+        // Without a size there is no pool, so no misses to count either
+        let pool = match pool_config.size {
+            0 => HashMap::new(),
+            _ => pool_config
+                .templates
+                .iter()
+                .map(|t| (*t, TemplatePool::default()))
+                .collect(),
+        };
+
         VmStore {
             vms: HashMap::new(),
+            pool_config,
+            pool,
+            pool_wake: Arc::new(Notify::new()),
+            retired: Vec::new(),
         }
     }
 
-    pub fn add_vm(&mut self, id: &str, vm: VmHandle) {
-        self.vms.insert(id.into(), Arc::new(vm));
+    /// Spawns a VM actor under the lowest free sequence number and adds it.
+    /// The VM boots once it is sent `start_vm`.
+    pub fn create_vm(
+        &mut self,
+        resources: VmResources,
+        heartbeat: HeartbeatConfig,
+    ) -> Result<Arc<VmHandle>, String> {
+        let vm = self.spawn_vm(resources, heartbeat)?;
+
+        self.vms.insert(vm.id.clone(), vm.clone());
+
+        Ok(vm)
     }
 
     pub fn get_vm(&self, id: &str) -> Option<Arc<VmHandle>> {
         self.vms.get(id).cloned()
     }
 
-    pub fn remove_vm(&mut self, id: &str) {
-        self.vms.remove(id);
+    /// Every VM handed out, ordered by sequence number
+    pub fn list(&self) -> Vec<Arc<VmHandle>> {
+        let mut vms: Vec<_> = self.vms.values().cloned().collect();
+        vms.sort_by_key(|vm| vm.seq);
+
+        vms
+    }
+
+    /// Takes the VM out of the store. Its sequence number stays reserved
+    /// until the VM is shut down.
+    pub fn remove_vm(&mut self, id: &str) -> Option<Arc<VmHandle>> {
+        let vm = self.vms.remove(id)?;
+        self.retired.push(vm.clone());
+
+        // A lent VM that does not come back frees its slot
+        if let Some(template) = self.pool.get_mut(&vm.resources)
+            && template.lent.remove(id)
+        {
+            self.pool_wake.notify_one();
+        }
+
+        Some(vm)
+    }
+
+    /// Every VM, pooled ones included, as well as removed VMs that are still
+    /// being torn down
+    pub fn remove_all(&mut self) -> Vec<Arc<VmHandle>> {
+        let mut vms: Vec<_> = self.vms.drain().map(|(_, vm)| vm).collect();
+        vms.extend(self.retired.drain(..).filter(|vm| !vm.is_finished()));
+
+        for template in self.pool.values_mut() {
+            vms.extend(template.idle.drain(..));
+            vms.extend(template.warming.drain().map(|(_, vm)| vm));
+            template.lent.clear();
+        }
+
+        vms
+    }
+
+    /// Hands out a healthy idle VM of the template, already booted, and
+    /// wakes the refill task. `None` when the pool has none, or does not
+    /// keep the template at all.
+    pub fn take_pooled(&mut self, resources: VmResources) -> Option<Arc<VmHandle>> {
+        let vm = self.take_idle(resources)?;
+        self.pool_wake.notify_one();
+
+        Some(vm)
+    }
+
+    /// Like `take_pooled`, for a one-shot run. Under `AfterUse::Recycle` the
+    /// VM keeps its slot until it is released, instead of the refill booting
+    /// a replacement for a VM that may come back.
+    pub fn lend_pooled(&mut self, resources: VmResources) -> Option<Arc<VmHandle>> {
+        if !self.recycles() {
+            return self.take_pooled(resources);
+        }
+
+        let vm = self.take_idle(resources)?;
+
+        if let Some(template) = self.pool.get_mut(&resources) {
+            template.lent.insert(vm.id.clone());
+        }
+
+        Some(vm)
     }
 
-    pub fn len(&self) -> usize {
-        self.vms.len()
+    fn take_idle(&mut self, resources: VmResources) -> Option<Arc<VmHandle>> {
+        let template = self.pool.get_mut(&resources)?;
+
+        let vm = template
+            .idle
+            .iter()
+            .position(|vm| vm.health() == Health::Healthy)
+            .and_then(|i| template.idle.remove(i));
+
+        match vm {
+            Some(vm) => {
+                template.hits += 1;
+                self.vms.insert(vm.id.clone(), vm.clone());
+
+                Some(vm)
+            }
+            None => {
+                template.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Whether VMs done with a one-shot run may go back to the pool
+    fn recycles(&self) -> bool {
+        self.pool_config.after_use == AfterUse::Recycle
+    }
+
+    /// Whether a VM done with a one-shot run has a place in the pool to go
+    /// back to: its own slot if it was lent, or one its template is short
+    /// of. Checked before resetting the guest for nothing.
+    pub fn can_recycle(&self, id: &str) -> bool {
+        let Some(vm) = self.vms.get(id) else {
+            return false;
+        };
+
+        self.recycles()
+            && self.pool.get(&vm.resources).is_some_and(|template| {
+                template.lent.contains(id) || template.slots() < self.pool_config.size
+            })
+    }
+
+    /// Removes a VM that is done with a one-shot run. Under
+    /// `AfterUse::Recycle` a reusable, healthy VM goes back to the pool if
+    /// it has a place there. Otherwise the VM is returned for teardown.
+    pub fn release_vm(&mut self, id: &str, reusable: bool) -> Option<Arc<VmHandle>> {
+        let recycle = reusable
+            && self.can_recycle(id)
+            && self
+                .vms
+                .get(id)
+                .is_some_and(|vm| vm.health() == Health::Healthy);
+
+        let vm = self.remove_vm(id)?;
+
+        match self.pool.get_mut(&vm.resources) {
+            Some(template) if recycle => {
+                self.retired.retain(|retired| retired.id != vm.id);
+                template.idle.push_back(vm);
+                None
+            }
+            _ => Some(vm),
+        }
+    }
+
+    /// Spawns the VMs each template is short of and tracks them as warming.
+    /// The caller boots them and reports back with `pool_ready`.
+    pub fn refill_pool(&mut self, heartbeat: HeartbeatConfig) -> Vec<Arc<VmHandle>> {
+        let mut spawned = Vec::new();
+
+        for resources in self.pool_config.templates.clone() {
+            let Some(template) = self.pool.get(&resources) else {
+                continue;
+            };
+
+            let missing = self.pool_config.size.saturating_sub(template.slots());
+
+            for _ in 0..missing {
+                // Out of slots, VMs handed out take precedence over the pool
+                let Ok(vm) = self.spawn_vm(resources, heartbeat) else {
+                    return spawned;
+                };
+
+                if let Some(template) = self.pool.get_mut(&resources) {
+                    template.warming.insert(vm.id.clone(), vm.clone());
+                }
+
+                spawned.push(vm);
+            }
+        }
+
+        spawned
+    }
+
+    /// Moves a warming VM to the idle ones once it booted. Returns it for
+    /// teardown when it did not.
+    pub fn pool_ready(&mut self, id: &str, booted: bool) -> Option<Arc<VmHandle>> {
+        let template = self
+            .pool
+            .values_mut()
+            .find(|template| template.warming.contains_key(id))?;
+
+        let vm = template.warming.remove(id)?;
+
+        if !booted {
+            self.retired.push(vm.clone());
+            return Some(vm);
+        }
+
+        template.idle.push_back(vm);
+
+        None
+    }
+
+    /// Takes idle VMs that stopped answering out of the pool
+    pub fn evict_unhealthy(&mut self) -> Vec<Arc<VmHandle>> {
+        let mut evicted = Vec::new();
+
+        for template in self.pool.values_mut() {
+            let (healthy, unhealthy) = template
+                .idle
+                .drain(..)
+                .partition(|vm| vm.health() == Health::Healthy);
+
+            template.idle = healthy;
+            evicted.extend(unhealthy);
+        }
+
+        self.retired.extend(evicted.iter().cloned());
+
+        evicted
+    }
+
+    pub fn pool_wake(&self) -> Arc<Notify> {
+        self.pool_wake.clone()
+    }
+
+    pub fn pool_stats(&self) -> PoolStats {
+        let templates = self
+            .pool_config
+            .templates
+            .iter()
+            .filter_map(|resources| Some((resources, self.pool.get(resources)?)))
+            .map(|(resources, template)| TemplateStats {
+                vcpu_count: resources.vcpu_count,
+                mem_size_mib: resources.mem_size_mib,
+                size: template.idle.len(),
+                warming: template.warming.len(),
+                hits: template.hits,
+                misses: template.misses,
+            })
+            .collect();
+
+        PoolStats { templates }
+    }
+
+    fn spawn_vm(
+        &mut self,
+        resources: VmResources,
+        heartbeat: HeartbeatConfig,
+    ) -> Result<Arc<VmHandle>, String> {
+        self.retired.retain(|vm| !vm.is_finished());
+
+        let seq = (1..=MAX_SEQ)
+            .find(|seq| !self.seq_in_use(*seq))
+            .ok_or_else(|| format!("All {} VM slots are in use", MAX_SEQ))?;
+
+        Ok(Arc::new(vm::spawn_vm(seq, resources, heartbeat)))
+    }
+
+    fn seq_in_use(&self, seq: usize) -> bool {
+        let pooled = self
+            .pool
+            .values()
+            .flat_map(|template| template.idle.iter().chain(template.warming.values()));
+
+        self.vms
+            .values()
+            .chain(pooled)
+            .chain(&self.retired)
+            .any(|vm| vm.seq == seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{mpsc, watch};
+
+    use super::*;
+    use crate::vm_handle::VmMessage;
+
+    /// A VM without an actor. The receiver stands in for its mailbox, and
+    /// dropping it finishes the VM.
+    struct FakeVm {
+        handle: Arc<VmHandle>,
+        _mailbox: mpsc::Receiver<VmMessage>,
+        _health: watch::Sender<Health>,
+    }
+
+    fn fake_vm(seq: usize) -> FakeVm {
+        let (tx, mailbox) = mpsc::channel(1);
+        let (health, health_rx) = watch::channel(Health::Healthy);
+
+        let handle = VmHandle::new(
+            format!("vm-{}", seq),
+            seq,
+            VmResources::default(),
+            tx,
+            health_rx,
+        );
+
+        FakeVm {
+            handle: Arc::new(handle),
+            _mailbox: mailbox,
+            _health: health,
+        }
+    }
+
+    fn pool_config(size: usize, after_use: AfterUse) -> PoolConfig {
+        PoolConfig {
+            size,
+            after_use,
+            ..Default::default()
+        }
+    }
+
+    fn template(store: &mut VmStore) -> &mut TemplatePool {
+        store.pool.get_mut(&VmResources::default()).unwrap()
+    }
+
+    #[test]
+    fn test_lent_vm_recycles_into_its_own_slot() {
+        let mut store = VmStore::new(pool_config(1, AfterUse::Recycle));
+        let vm = fake_vm(1);
+        template(&mut store).idle.push_back(vm.handle.clone());
+
+        let lent = store.lend_pooled(VmResources::default()).unwrap();
+        assert_eq!(lent.id, "vm-1");
+
+        // Its slot is held, so the refill has nothing to boot
+        assert!(store.refill_pool(HeartbeatConfig::default()).is_empty());
+        assert!(store.can_recycle("vm-1"));
+
+        assert!(store.release_vm("vm-1", true).is_none());
+        assert_eq!(template(&mut store).idle.len(), 1);
+        assert!(template(&mut store).lent.is_empty());
+        assert!(store.get_vm("vm-1").is_none());
+        assert!(!store.retired.iter().any(|vm| vm.id == "vm-1"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_run_frees_the_lent_slot() {
+        let mut store = VmStore::new(pool_config(1, AfterUse::Recycle));
+        let vm = fake_vm(1);
+        template(&mut store).idle.push_back(vm.handle.clone());
+
+        store.lend_pooled(VmResources::default()).unwrap();
+
+        let released = store.release_vm("vm-1", false).unwrap();
+        assert_eq!(released.id, "vm-1");
+        assert!(template(&mut store).lent.is_empty());
+
+        let spawned = store.refill_pool(HeartbeatConfig::default());
+        assert_eq!(spawned.len(), 1);
+
+        // vm-1 is still being torn down
+        assert_eq!(spawned[0].seq, 2);
+    }
+
+    #[tokio::test]
+    async fn test_destroy_policy_replaces_taken_vms() {
+        let mut store = VmStore::new(pool_config(1, AfterUse::Destroy));
+        let vm = fake_vm(1);
+        template(&mut store).idle.push_back(vm.handle.clone());
+
+        store.lend_pooled(VmResources::default()).unwrap();
+        assert!(template(&mut store).lent.is_empty());
+        assert!(!store.can_recycle("vm-1"));
+
+        assert_eq!(store.refill_pool(HeartbeatConfig::default()).len(), 1);
+        assert!(store.release_vm("vm-1", true).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_unlent_vm_recycles_only_into_a_short_pool() {
+        let mut store = VmStore::new(pool_config(1, AfterUse::Recycle));
+        let first = fake_vm(1);
+        let second = fake_vm(2);
+
+        store.vms.insert("vm-1".to_string(), first.handle.clone());
+        store.vms.insert("vm-2".to_string(), second.handle.clone());
+
+        assert!(store.can_recycle("vm-1"));
+        assert!(store.release_vm("vm-1", true).is_none());
+
+        // The pool is full now
+        assert!(!store.can_recycle("vm-2"));
+        assert!(store.release_vm("vm-2", true).is_some());
+        assert!(store.refill_pool(HeartbeatConfig::default()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_refill_and_pool_ready_account_for_slots() {
+        let mut store = VmStore::new(pool_config(2, AfterUse::Destroy));
+
+        let spawned = store.refill_pool(HeartbeatConfig::default());
+        assert_eq!(spawned.len(), 2);
+        assert_eq!(template(&mut store).warming.len(), 2);
+
+        // Warming VMs hold their slots
+        assert!(store.refill_pool(HeartbeatConfig::default()).is_empty());
+
+        assert!(store.pool_ready(&spawned[0].id, true).is_none());
+        assert_eq!(template(&mut store).idle.len(), 1);
+
+        let failed = store.pool_ready(&spawned[1].id, false).unwrap();
+        assert_eq!(failed.id, spawned[1].id);
+        assert!(template(&mut store).warming.is_empty());
+
+        // The failed VM keeps its sequence number until it is torn down
+        let replacement = store.refill_pool(HeartbeatConfig::default());
+        assert_eq!(replacement.len(), 1);
+        assert_ne!(replacement[0].seq, spawned[0].seq);
+        assert_ne!(replacement[0].seq, spawned[1].seq);
+
+        assert!(store.pool_ready("vm-unknown", true).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_removed_vm_keeps_its_seq_until_finished() {
+        let mut store = VmStore::new(PoolConfig::default());
+        let vm = fake_vm(1);
+        store.vms.insert("vm-1".to_string(), vm.handle.clone());
+
+        assert!(store.remove_vm("vm-1").is_some());
+
+        let next = store
+            .create_vm(VmResources::default(), HeartbeatConfig::default())
+            .unwrap();
+        assert_eq!(next.seq, 2);
+
+        // Dropping the mailbox finishes the VM, which frees its seq
+        drop(vm);
+
+        let reused = store
+            .create_vm(VmResources::default(), HeartbeatConfig::default())
+            .unwrap();
+        assert_eq!(reused.seq, 1);
+        assert!(store.retired.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spawn_vm_stops_at_max_seq() {
+        let mut store = VmStore::new(PoolConfig::default());
+
+        let vms: Vec<_> = (1..MAX_SEQ).map(fake_vm).collect();
+        for vm in &vms {
+            store.vms.insert(vm.handle.id.clone(), vm.handle.clone());
+        }
+
+        let last = store
+            .create_vm(VmResources::default(), HeartbeatConfig::default())
+            .unwrap();
+        assert_eq!(last.seq, MAX_SEQ);
+
+        // Slot 255 is never handed out
+        assert!(
+            store
+                .create_vm(VmResources::default(), HeartbeatConfig::default())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_remove_all_includes_retired_vms() {
+        let mut store = VmStore::new(PoolConfig::default());
+        let running = fake_vm(1);
+        let finished = fake_vm(2);
+
+        store.vms.insert("vm-1".to_string(), running.handle.clone());
+        store
+            .vms
+            .insert("vm-2".to_string(), finished.handle.clone());
+
+        store.remove_vm("vm-1").unwrap();
+        store.remove_vm("vm-2").unwrap();
+
+        let handle = finished.handle.clone();
+        drop(finished);
+        assert!(handle.is_finished());
+
+        let removed = store.remove_all();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].id, "vm-1");
+        assert!(store.retired.is_empty());
     }
 }
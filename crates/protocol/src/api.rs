@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{CommandOutput, ErrorKind, OutputChunk, ProcessExit, tar::Compression};
+
+/// Body of `POST /vms`. Unset resources default to 1 vCPU and 512 MiB.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CreateVm {
+    #[serde(default)]
+    pub vcpu_count: Option<u32>,
+    #[serde(default)]
+    pub mem_size_mib: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    /// Booting, or not connected yet
+    Starting,
+    /// Connected and answering pings
+    Healthy,
+    /// Missed too many pings, or failed the handshake
+    Unhealthy,
+    /// Torn down, by request or by a recovery action
+    Stopped,
+}
+
+/// Body of `GET /vms/{id}/health`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmHealth {
+    pub health: Health,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VmInfo {
+    pub id: String,
+    pub health: Health,
+    pub vcpu_count: u32,
+    pub mem_size_mib: u32,
+}
+
+/// The `options` part of a `POST /vms/{id}/workspace` form. The tarball
+/// itself goes in the `archive` part.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WorkspaceRequest {
+    /// Script to run, relative to the workspace root
+    pub entrypoint: String,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Same as `WorkspaceRunOptions::artifacts`
+    #[serde(default)]
+    pub artifacts: Vec<String>,
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+/// The `options` part of a `POST /run` form: the workspace run, plus the
+/// resources of the VM booted for it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RunRequest {
+    #[serde(default)]
+    pub vm: CreateVm,
+    #[serde(flatten)]
+    pub workspace: WorkspaceRequest,
+}
+
+/// Body of a successful `POST /run`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunResult {
+    pub output: CommandOutput,
+    /// Tarball of the files matching `WorkspaceRequest::artifacts`, copied
+    /// out before the VM was destroyed
+    pub artifacts: Option<Vec<u8>>,
+}
+
+/// Body of `GET /pool`, one entry per pooled template
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PoolStats {
+    pub templates: Vec<TemplateStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TemplateStats {
+    pub vcpu_count: u32,
+    pub mem_size_mib: u32,
+    /// Booted VMs waiting to be handed out
+    pub size: usize,
+    /// VMs booting to refill the pool
+    pub warming: usize,
+    /// Requests served from the pool
+    pub hits: u64,
+    /// Requests that found the pool empty and booted a VM themselves
+    pub misses: u64,
+}
+
+/// Body of every failed API response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiError {
+    /// Set when the guest reported the failure
+    #[serde(default)]
+    pub kind: Option<ErrorKind>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            Some(kind) => write!(f, "{:?}: {}", kind, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Reply to a run started with `?detach=true`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobCreated {
+    pub job_id: String,
+}
+
+/// Body of `GET /vms/{id}/jobs/{job_id}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Finished { output: CommandOutput },
+    Failed { error: ApiError },
+}
+
+/// One event of a streamed exec. `Exit` or `Error` ends the stream.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExecEvent {
+    Output(OutputChunk),
+    Exit(ProcessExit),
+    Error(ApiError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exec_event_json() {
+        let event = ExecEvent::Output(OutputChunk {
+            stream: crate::OutputStream::Stdout,
+            seq: 3,
+            data: b"hi".to_vec(),
+        });
+
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"output","stream":"Stdout","seq":3,"data":[104,105]}"#
+        );
+
+        match serde_json::from_str(&json).unwrap() {
+            ExecEvent::Output(chunk) => assert_eq!(chunk.data, b"hi"),
+            e => panic!("unexpected event: {:?}", e),
+        }
+    }
+}
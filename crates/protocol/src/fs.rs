@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+
+/// Filesystem operations the guest runs on behalf of the host. Paths are
+/// absolute paths inside the guest.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum FsRequest {
+    /// Replied to with `DirListing`
+    ListDir { path: String },
+    /// Replied to with `FileStat`. Symlinks are not followed.
+    Stat { path: String },
+    /// Replied to with `FileData`. Reads at most `length` bytes from
+    /// `offset`, capped by the guest.
+    ReadFile {
+        path: String,
+        offset: u64,
+        length: u64,
+    },
+    /// Creates or truncates the file, or appends to it. `mode` applies to
+    /// the file afterwards. Replied to with `FsDone`.
+    WriteFile {
+        path: String,
+        data: Vec<u8>,
+        append: bool,
+        mode: Option<u32>,
+    },
+    /// Replied to with `FsDone`
+    CreateDir { path: String, recursive: bool },
+    /// Removes a file, symlink or empty directory, or a whole tree with
+    /// `recursive`. Replied to with `FsDone`.
+    Remove { path: String, recursive: bool },
+    /// Replied to with `FsDone`
+    Rename { from: String, to: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileStat {
+    pub kind: FileKind,
+    pub size: u64,
+    /// Permission bits
+    pub mode: u32,
+    /// Last modification as seconds since the Unix epoch
+    pub modified: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub stat: FileStat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsErrorKind {
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    InvalidInput,
+    Other,
+}
+
+/// A failed `FsRequest`, sent instead of its regular reply
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsError {
+    pub kind: FsErrorKind,
+    pub message: String,
+}
+
+impl std::fmt::Display for FsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for FsError {}
+
+impl From<std::io::Error> for FsError {
+    fn from(e: std::io::Error) -> Self {
+        let kind = match e.kind() {
+            std::io::ErrorKind::NotFound => FsErrorKind::NotFound,
+            std::io::ErrorKind::PermissionDenied => FsErrorKind::PermissionDenied,
+            std::io::ErrorKind::AlreadyExists => FsErrorKind::AlreadyExists,
+            std::io::ErrorKind::NotADirectory => FsErrorKind::NotADirectory,
+            std::io::ErrorKind::IsADirectory => FsErrorKind::IsADirectory,
+            std::io::ErrorKind::DirectoryNotEmpty => FsErrorKind::DirectoryNotEmpty,
+            std::io::ErrorKind::InvalidInput => FsErrorKind::InvalidInput,
+            _ => FsErrorKind::Other,
+        };
+
+        FsError {
+            kind,
+            message: e.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fs_error_from_io_error() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let err: FsError = std::fs::read(dir.path().join("missing"))
+            .unwrap_err()
+            .into();
+        assert_eq!(err.kind, FsErrorKind::NotFound);
+
+        let err: FsError = std::fs::create_dir(dir.path()).unwrap_err().into();
+        assert_eq!(err.kind, FsErrorKind::AlreadyExists);
+
+        std::fs::write(dir.path().join("file"), b"data").unwrap();
+
+        let err: FsError = std::fs::remove_dir(dir.path()).unwrap_err().into();
+        assert_eq!(err.kind, FsErrorKind::DirectoryNotEmpty);
+    }
+}
@@ -1,61 +1,595 @@
+pub mod api;
+pub mod fs;
 pub mod tar;
+pub mod transfer;
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::OnceLock};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-#[derive(Serialize, Deserialize)]
+/// Envelope version of frames with a JSON body
+pub const JSON_VERSION: u32 = 1;
+/// Envelope version of frames with a bincode body. bincode is positional
+/// and ignores `#[serde(default)]`, so this is bumped on every change to the
+/// layout of `Envelope`: a `Message` variant added, a field added to or
+/// reordered in any payload, an enum variant inserted anywhere.
+pub const BINARY_VERSION: u32 = 3;
+
+/// Oldest envelope version this build still reads and writes
+pub const MIN_VERSION: u32 = JSON_VERSION;
+/// Newest envelope version this build reads and writes
+pub const MAX_VERSION: u32 = BINARY_VERSION;
+
+/// Set to `json` to send every frame as JSON, which is easier to inspect
+pub const WIRE_FORMAT_ENV: &str = "SECEX_WIRE_FORMAT";
+
+/// Largest frame body `send_msg` and `recv_msg` accept
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Per-connection settings for framing messages
+#[derive(Debug, Clone, Copy)]
+pub struct WireOptions {
+    pub max_frame_size: usize,
+    /// Newest envelope version the peer understands, as agreed in `Hello`.
+    /// Messages are never sent with a newer version and frames with one are
+    /// rejected.
+    pub max_version: u32,
+}
+
+impl Default for WireOptions {
+    fn default() -> Self {
+        WireOptions {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_version: MAX_VERSION,
+        }
+    }
+}
+
+/// Why sending or receiving a frame failed
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The peer closed the connection, between frames or in the middle of one
+    Disconnected,
+    /// A received frame body does not decode as an envelope of its version.
+    /// The whole frame was consumed, so the next one can still be read.
+    Malformed(Box<dyn std::error::Error + Send + Sync>),
+    /// A frame body is larger than the configured limit. Returned before
+    /// anything is written on send and before the body is read on receive,
+    /// in which case the stream is no longer aligned to a frame and must be
+    /// closed.
+    FrameTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// A message could not be encoded
+    Serde(Box<dyn std::error::Error + Send + Sync>),
+    /// A frame or a peer's `Hello` uses an envelope version outside the
+    /// range this side supports. A rejected frame was consumed in full.
+    UnsupportedVersion {
+        version: u32,
+        min: u32,
+        max: u32,
+    },
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Disconnected => write!(f, "Peer disconnected"),
+            ProtocolError::Malformed(e) => write!(f, "Malformed frame: {}", e),
+            ProtocolError::FrameTooLarge { size, max } => write!(
+                f,
+                "Frame of {} bytes exceeds the limit of {} bytes",
+                size, max
+            ),
+            ProtocolError::Serde(e) => write!(f, "Failed to encode message: {}", e),
+            ProtocolError::UnsupportedVersion { version, min, max } => write!(
+                f,
+                "Envelope version {} is outside the supported range {}..={}",
+                version, min, max
+            ),
+            ProtocolError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Malformed(e) | ProtocolError::Serde(e) => Some(e.as_ref()),
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted => ProtocolError::Disconnected,
+            _ => ProtocolError::Io(e),
+        }
+    }
+}
+
+/// Wire layout of a frame: body length as u32, envelope version as u32, then
+/// the envelope body encoded as the version prescribes. Both integers are
+/// big-endian.
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Envelope {
+    /// Carried in the frame header, where it selects the body encoding
+    #[serde(skip)]
     pub version: u32,
+    /// Identifies the request a message belongs to. Replies echo the id of
+    /// the request that caused them.
+    pub request_id: u64,
     pub message: Message,
 }
 
+/// New variants go at the end. bincode encodes the variant's position, so an
+/// insertion changes the meaning of every variant after it.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
-    Hello,
+    /// Sent by the host right after connecting and answered with the
+    /// guest's own `Hello`. Both sides then use the newest version in both
+    /// ranges.
+    Hello(Hello),
     RunCommand(RunCommand),
     RunWorkspace(WorkspaceRunOptions),
     CommandOutput(CommandOutput),
-    SendFile(FileTransfer),
+    OutputChunk(OutputChunk),
+    ProcessExit(ProcessExit),
+    /// Opens a file upload. The guest answers with `UploadReady`, then
+    /// acknowledges every `UploadChunk` with an `UploadAck` and replies to
+    /// `UploadEnd` with `UploadComplete` once the checksum matches.
+    UploadBegin(UploadBegin),
+    /// Chunk `seq` of the upload started by `request_id`. Every chunk but the
+    /// last carries exactly `UploadBegin::chunk_size` bytes.
+    UploadChunk {
+        request_id: u64,
+        seq: u64,
+        data: Vec<u8>,
+    },
+    /// No more chunks follow for the upload started by `request_id`
+    UploadEnd {
+        request_id: u64,
+    },
+    /// First chunk the guest still needs. Chunks of an earlier, interrupted
+    /// upload of the same file are kept, so this is zero only for a new one.
+    UploadReady {
+        next_seq: u64,
+    },
+    /// Chunk `seq` is on the guest's disk
+    UploadAck {
+        seq: u64,
+    },
+    UploadComplete(UploadComplete),
+    /// Asks the guest for the contents of a file. The guest replies with a
+    /// `DownloadBegin`, the `DownloadChunk`s and a final `DownloadEnd`.
+    FetchFile {
+        path: String,
+    },
+    /// Same as `FetchFile`, for a tarball of a directory
+    FetchDir {
+        path: String,
+    },
+    DownloadBegin(FileInfo),
+    /// Chunk `seq` of a download, all but the last are `chunk_size` long
+    DownloadChunk {
+        seq: u64,
+        data: Vec<u8>,
+    },
+    DownloadEnd,
+    /// An upload or download was rejected or did not match its checksum
+    TransferFailed {
+        reason: String,
+    },
+    /// A filesystem operation on the guest, see `FsRequest` for its reply
+    Fs(fs::FsRequest),
+    DirListing(Vec<fs::DirEntry>),
+    FileStat(fs::FileStat),
+    /// Bytes read by `FsRequest::ReadFile`. `size` is the length of the
+    /// whole file, so a short read before it means the guest capped it.
+    FileData {
+        data: Vec<u8>,
+        size: u64,
+    },
+    /// A `FsRequest` that has no other result succeeded
+    FsDone,
+    FsError(fs::FsError),
+    /// Stops a running request: SIGTERM, then SIGKILL after a grace period
+    Cancel {
+        request_id: u64,
+    },
+    /// Delivers a signal to the process group of a running request
+    Signal {
+        request_id: u64,
+        signal: i32,
+    },
+    /// Starts an interactive session on a pseudo terminal. The guest replies
+    /// with `OutputChunk`s of terminal output and a final `ProcessExit`.
+    PtyOpen(PtyOpen),
+    /// Keystrokes for the session started by `request_id`
+    PtyInput {
+        request_id: u64,
+        data: Vec<u8>,
+    },
+    PtyResize {
+        request_id: u64,
+        size: TerminalSize,
+    },
+    /// More input for a `RunCommand` submitted with `keep_stdin_open`
+    StdinChunk {
+        request_id: u64,
+        data: Vec<u8>,
+    },
+    /// Final reply to a request that failed before it could produce its
+    /// regular result
+    Error {
+        request_id: u64,
+        kind: ErrorKind,
+        message: String,
+    },
+    /// Liveness probe from the host, answered right away with a `Pong`
+    /// carrying the same nonce
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+    /// Closes the stdin of a `RunCommand`, the process then reads EOF
+    StdinClose {
+        request_id: u64,
+    },
     Shutdown,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Message {
+    /// Whether this message is the last reply the guest sends for a request
+    pub fn is_final(&self) -> bool {
+        !matches!(
+            self,
+            Message::OutputChunk(_)
+                | Message::UploadReady { .. }
+                | Message::UploadAck { .. }
+                | Message::DownloadBegin(_)
+                | Message::DownloadChunk { .. }
+        )
+    }
+
+    /// Whether the message can carry enough raw bytes that JSON, which
+    /// writes them as an array of decimal numbers, would bloat it
+    pub fn has_bulk_payload(&self) -> bool {
+        match self {
+            Message::RunCommand(cmd) => cmd.stdin.is_some(),
+            Message::RunWorkspace(wo) => matches!(wo.source, WorkspaceSource::Inline(_)),
+            Message::CommandOutput(_)
+            | Message::OutputChunk(_)
+            | Message::UploadChunk { .. }
+            | Message::DownloadChunk { .. }
+            | Message::Fs(fs::FsRequest::WriteFile { .. })
+            | Message::FileData { .. }
+            | Message::PtyInput { .. }
+            | Message::StdinChunk { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Capability the receiving side needs to handle this message
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            Message::RunCommand(cmd) if cmd.stream_output => Some(Capability::Streaming),
+            Message::RunWorkspace(wo) if wo.compression != tar::Compression::None => {
+                Some(Capability::Compression)
+            }
+            Message::RunWorkspace(wo) if wo.stream_output => Some(Capability::Streaming),
+            Message::PtyOpen(_) => Some(Capability::Pty),
+            Message::Fs(_) => Some(Capability::FileOps),
+            Message::Ping { .. } => Some(Capability::Heartbeat),
+            Message::UploadBegin(_) | Message::FetchFile { .. } | Message::FetchDir { .. } => {
+                Some(Capability::FileTransfer)
+            }
+            _ => None,
+        }
+    }
+
+    /// Envelope version to send this message with
+    fn wire_version(&self, max_version: u32) -> u32 {
+        match self.has_bulk_payload() && !json_only() && max_version >= BINARY_VERSION {
+            true => BINARY_VERSION,
+            false => JSON_VERSION,
+        }
+    }
+}
+
+fn json_only() -> bool {
+    static JSON_ONLY: OnceLock<bool> = OnceLock::new();
+
+    *JSON_ONLY.get_or_init(|| std::env::var(WIRE_FORMAT_ENV).is_ok_and(|v| v == "json"))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The workspace has no file at the entrypoint path
+    EntrypointNotFound,
+    /// The workspace tarball could not be unpacked
+    ExtractFailed,
+    /// The program could not be started
+    SpawnFailed,
+    /// The request ran out of time before its program started
+    Timeout,
+    /// The guest lacks the capability the request needs
+    Unsupported,
+    /// Anything else that went wrong on the guest
+    Internal,
+}
+
+/// A failed request, as reported by a `Message::Error`
+#[derive(Debug)]
+pub struct RequestError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl RequestError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        RequestError {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Optional features a side supports on top of running commands
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// `OutputChunk`s and `ProcessExit` for `stream_output` runs
+    Streaming,
+    Pty,
+    /// `FsRequest`s
+    FileOps,
+    /// Chunked uploads and downloads
+    FileTransfer,
+    /// Compressed workspace tarballs
+    Compression,
+    /// Answers `Ping` with `Pong`
+    Heartbeat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    /// Announces the version range of this build
+    pub fn new(capabilities: Vec<Capability>) -> Self {
+        Hello {
+            min_version: MIN_VERSION,
+            max_version: MAX_VERSION,
+            capabilities,
+        }
+    }
+
+    /// Newest version both this side and `peer` speak. Besides JSON a build
+    /// only speaks the binary layout of its own `BINARY_VERSION`, which every
+    /// layout change bumps, so peers announcing different newest versions
+    /// settle on JSON.
+    pub fn negotiate(&self, peer: &Hello) -> Result<u32, ProtocolError> {
+        let version = match self.max_version == peer.max_version {
+            true => self.max_version,
+            false => JSON_VERSION,
+        };
+
+        if version < self.min_version.max(peer.min_version) {
+            return Err(ProtocolError::UnsupportedVersion {
+                version: match peer.max_version < self.min_version {
+                    true => peer.max_version,
+                    false => peer.min_version,
+                },
+                min: self.min_version,
+                max: self.max_version,
+            });
+        }
+
+        Ok(version)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CommandOutput {
-    pub output: String,
+    /// Exit code of the process, `None` if it was terminated by a signal
+    pub exit_code: Option<i32>,
+    /// Signal that terminated the process, if any
+    pub signal: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Wall-clock time from spawn to exit
+    pub duration_ms: u64,
+    /// Set when stdout or stderr exceeded the guest's output limit and was cut
+    pub truncated: bool,
+    /// Set when the process was killed for exceeding its timeout
+    pub timed_out: bool,
+    /// Set when the process was stopped by a `Cancel` request
+    pub cancelled: bool,
+    /// Guest path of a tarball with the files matching
+    /// `WorkspaceRunOptions::artifacts`, ready for `FetchFile`
+    pub artifacts: Option<String>,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
 }
 
+/// A piece of process output, sent while the process is still running.
+/// `seq` increases by one per chunk across both streams of a request.
 #[derive(Serialize, Deserialize, Debug)]
-pub struct FileTransfer {
+pub struct OutputChunk {
+    pub stream: OutputStream,
+    pub seq: u64,
     pub data: Vec<u8>,
 }
 
+/// Final message of a streamed run, sent after the last `OutputChunk`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProcessExit {
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration_ms: u64,
+    pub timed_out: bool,
+    pub cancelled: bool,
+    /// Same as `CommandOutput::artifacts`
+    pub artifacts: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadBegin {
+    /// Absolute destination path on the guest. Missing parent directories
+    /// are created.
+    pub path: String,
+    /// Total number of bytes
+    pub size: u64,
+    /// Permission bits of the finished file
+    pub mode: u32,
+    /// Hex encoded SHA-256 of the whole file
+    pub sha256: String,
+    pub chunk_size: u64,
+}
+
+/// Describes a file before its chunks follow
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileInfo {
+    pub size: u64,
+    pub mode: u32,
+    /// Hex encoded SHA-256 of the whole file
+    pub sha256: String,
+    pub chunk_size: u64,
+}
+
+/// Final reply to a verified upload
 #[derive(Serialize, Deserialize, Debug)]
+pub struct UploadComplete {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct RunCommand {
     pub command: String,
     pub args: Vec<String>,
     pub env: HashMap<String, String>,
     pub working_dir: Option<String>,
+    /// Stream output as `OutputChunk`s followed by a `ProcessExit` instead of
+    /// replying with a single `CommandOutput`
+    pub stream_output: bool,
+    /// Kill the process if it runs longer than this
+    pub timeout_ms: Option<u64>,
+    /// Written to the process's stdin right after it starts
+    pub stdin: Option<Vec<u8>>,
+    /// Keep stdin open after the initial payload for `StdinChunk`s until a
+    /// `StdinClose`. Without it stdin is closed once `stdin` is written.
+    pub keep_stdin_open: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TerminalSize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PtyOpen {
+    pub command: String,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub size: TerminalSize,
+}
+
+/// Tarball a workspace is unpacked from
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WorkspaceSource {
+    /// The tarball itself, sent along with the request
+    Inline(Vec<u8>),
+    /// Path of a tarball already on the guest, e.g. sent with `UploadBegin`.
+    /// It must live outside the workspace directory, which is recreated.
+    Archive(String),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WorkspaceRunOptions {
-    pub data: Vec<u8>,
+    pub source: WorkspaceSource,
     pub entrypoint: String,
+    /// Same as `RunCommand::stream_output`
+    pub stream_output: bool,
+    /// Same as `RunCommand::timeout_ms`
+    pub timeout_ms: Option<u64>,
+    /// Glob patterns, relative to the workspace, of files to pack into a
+    /// tarball once the entrypoint exits
+    pub artifacts: Vec<String>,
+    /// How the workspace tarball is compressed
+    #[serde(default)]
+    pub compression: tar::Compression,
 }
 
 pub async fn send_msg(
     stream: &mut (impl AsyncWriteExt + std::marker::Unpin),
+    request_id: u64,
+    msg: Message,
+) -> Result<(), ProtocolError> {
+    send_msg_with(stream, request_id, msg, &WireOptions::default()).await
+}
+
+pub async fn send_msg_with(
+    stream: &mut (impl AsyncWriteExt + std::marker::Unpin),
+    request_id: u64,
     msg: Message,
-) -> Result<(), Box<dyn std::error::Error>> {
+    options: &WireOptions,
+) -> Result<(), ProtocolError> {
     let env = Envelope {
-        version: 1,
+        version: msg.wire_version(options.max_version),
+        request_id,
         message: msg,
     };
 
-    let data = serde_json::to_vec(&env)?;
+    let data = match env.version {
+        BINARY_VERSION => bincode::serde::encode_to_vec(&env, bincode::config::standard())
+            .map_err(|e| ProtocolError::Serde(e.into()))?,
+        _ => serde_json::to_vec(&env).map_err(|e| ProtocolError::Serde(e.into()))?,
+    };
+
+    if data.len() > options.max_frame_size {
+        return Err(ProtocolError::FrameTooLarge {
+            size: data.len(),
+            max: options.max_frame_size,
+        });
+    }
 
     stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&env.version.to_be_bytes()).await?;
     stream.write_all(&data).await?;
 
     Ok(())
@@ -63,16 +597,496 @@ pub async fn send_msg(
 
 pub async fn recv_msg(
     stream: &mut (impl AsyncReadExt + std::marker::Unpin),
-) -> Result<Message, Box<dyn std::error::Error>> {
+) -> Result<Envelope, ProtocolError> {
+    recv_msg_with(stream, &WireOptions::default()).await
+}
+
+pub async fn recv_msg_with(
+    stream: &mut (impl AsyncReadExt + std::marker::Unpin),
+    options: &WireOptions,
+) -> Result<Envelope, ProtocolError> {
     let mut len_buf = [0u8; 4];
     stream.read_exact(&mut len_buf).await?;
 
     let len = u32::from_be_bytes(len_buf) as usize;
-    let mut msg_buf = vec![0u8; len];
 
+    if len > options.max_frame_size {
+        return Err(ProtocolError::FrameTooLarge {
+            size: len,
+            max: options.max_frame_size,
+        });
+    }
+
+    let mut version_buf = [0u8; 4];
+    stream.read_exact(&mut version_buf).await?;
+
+    let version = u32::from_be_bytes(version_buf);
+
+    // The body is read regardless, so a rejected frame leaves the stream
+    // aligned for the next one
+    let mut msg_buf = vec![0u8; len];
     stream.read_exact(&mut msg_buf).await?;
 
-    let envelope: Envelope = serde_json::from_slice(&msg_buf)?;
+    // Binary frames of another version have a layout this build cannot read
+    let supported =
+        version == JSON_VERSION || (version == BINARY_VERSION && version <= options.max_version);
+
+    if !supported {
+        return Err(ProtocolError::UnsupportedVersion {
+            version,
+            min: MIN_VERSION,
+            max: options.max_version,
+        });
+    }
+
+    let mut envelope: Envelope = match version {
+        BINARY_VERSION => {
+            bincode::serde::decode_from_slice(&msg_buf, bincode::config::standard())
+                .map_err(|e| ProtocolError::Malformed(e.into()))?
+                .0
+        }
+        _ => serde_json::from_slice(&msg_buf).map_err(|e| ProtocolError::Malformed(e.into()))?,
+    };
+
+    envelope.version = version;
+
+    Ok(envelope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_id_round_trip() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let hello = Message::Hello(Hello::new(vec![Capability::Pty]));
+
+        send_msg(&mut client, 42, hello).await.unwrap();
+
+        let envelope = recv_msg(&mut server).await.unwrap();
+
+        assert_eq!(envelope.request_id, 42);
+        assert_eq!(envelope.version, JSON_VERSION);
+        assert!(matches!(envelope.message, Message::Hello(_)));
+    }
+
+    #[tokio::test]
+    async fn test_recv_rejects_oversized_frame() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        client.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        client.write_all(&JSON_VERSION.to_be_bytes()).await.unwrap();
+
+        let options = WireOptions {
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+
+        let err = recv_msg_with(&mut server, &options).await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            ProtocolError::FrameTooLarge { size, max: 1024 } if size == u32::MAX as usize
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_rejects_oversized_frame() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let msg = Message::StdinChunk {
+            request_id: 1,
+            data: vec![0u8; 2048],
+        };
+
+        let options = WireOptions {
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+
+        let err = send_msg_with(&mut client, 2, msg, &options)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ProtocolError::FrameTooLarge { .. }));
+
+        // Nothing was written, so the stream still lines up with frames
+        send_msg(&mut client, 3, Message::Shutdown).await.unwrap();
+
+        let envelope = recv_msg(&mut server).await.unwrap();
+
+        assert_eq!(envelope.request_id, 3);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_payload_uses_binary_encoding() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+
+        let data = vec![255u8; 10_000];
+        let msg = Message::RunWorkspace(WorkspaceRunOptions {
+            source: WorkspaceSource::Inline(data.clone()),
+            entrypoint: "run.sh".to_string(),
+            stream_output: false,
+            timeout_ms: None,
+            artifacts: Vec::new(),
+            compression: tar::Compression::None,
+        });
+
+        send_msg(&mut client, 7, msg).await.unwrap();
+
+        let mut len_buf = [0u8; 4];
+        server.read_exact(&mut len_buf).await.unwrap();
+
+        // JSON would spend up to four bytes per payload byte
+        assert!((u32::from_be_bytes(len_buf) as usize) < data.len() + 64);
+
+        let mut version_buf = [0u8; 4];
+        server.read_exact(&mut version_buf).await.unwrap();
+
+        assert_eq!(u32::from_be_bytes(version_buf), BINARY_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_binary_round_trip() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+
+        let msg = Message::OutputChunk(OutputChunk {
+            stream: OutputStream::Stderr,
+            seq: 3,
+            data: b"partial output".to_vec(),
+        });
+
+        send_msg(&mut client, 9, msg).await.unwrap();
+
+        let envelope = recv_msg(&mut server).await.unwrap();
+
+        assert_eq!(envelope.request_id, 9);
+        assert_eq!(envelope.version, BINARY_VERSION);
+
+        match envelope.message {
+            Message::OutputChunk(chunk) => {
+                assert_eq!(chunk.stream, OutputStream::Stderr);
+                assert_eq!(chunk.seq, 3);
+                assert_eq!(chunk.data, b"partial output");
+            }
+            m => panic!("Unexpected message: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_binary_layout_is_pinned() {
+        fn encode(message: Message) -> Vec<u8> {
+            let env = Envelope {
+                version: BINARY_VERSION,
+                request_id: 1,
+                message,
+            };
+
+            bincode::serde::encode_to_vec(&env, bincode::config::standard()).unwrap()
+        }
+
+        // A layout change must come with a new version, and new pins
+        assert_eq!(BINARY_VERSION, 3);
+
+        let info = || FileInfo {
+            size: 0,
+            mode: 0,
+            sha256: String::new(),
+            chunk_size: 0,
+        };
+
+        let variants = [
+            Message::Hello(Hello::new(Vec::new())),
+            Message::RunCommand(RunCommand::default()),
+            Message::RunWorkspace(WorkspaceRunOptions {
+                source: WorkspaceSource::Archive(String::new()),
+                entrypoint: String::new(),
+                stream_output: false,
+                timeout_ms: None,
+                artifacts: Vec::new(),
+                compression: tar::Compression::None,
+            }),
+            Message::CommandOutput(CommandOutput {
+                exit_code: None,
+                signal: None,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+                duration_ms: 0,
+                truncated: false,
+                timed_out: false,
+                cancelled: false,
+                artifacts: None,
+            }),
+            Message::OutputChunk(OutputChunk {
+                stream: OutputStream::Stdout,
+                seq: 0,
+                data: Vec::new(),
+            }),
+            Message::ProcessExit(ProcessExit {
+                exit_code: None,
+                signal: None,
+                duration_ms: 0,
+                timed_out: false,
+                cancelled: false,
+                artifacts: None,
+            }),
+            Message::UploadBegin(UploadBegin {
+                path: String::new(),
+                size: 0,
+                mode: 0,
+                sha256: String::new(),
+                chunk_size: 0,
+            }),
+            Message::UploadChunk {
+                request_id: 0,
+                seq: 0,
+                data: Vec::new(),
+            },
+            Message::UploadEnd { request_id: 0 },
+            Message::UploadReady { next_seq: 0 },
+            Message::UploadAck { seq: 0 },
+            Message::UploadComplete(UploadComplete {
+                path: String::new(),
+                size: 0,
+                sha256: String::new(),
+            }),
+            Message::FetchFile {
+                path: String::new(),
+            },
+            Message::FetchDir {
+                path: String::new(),
+            },
+            Message::DownloadBegin(info()),
+            Message::DownloadChunk {
+                seq: 0,
+                data: Vec::new(),
+            },
+            Message::DownloadEnd,
+            Message::TransferFailed {
+                reason: String::new(),
+            },
+            Message::Fs(fs::FsRequest::ListDir {
+                path: String::new(),
+            }),
+            Message::DirListing(Vec::new()),
+            Message::FileStat(fs::FileStat {
+                kind: fs::FileKind::File,
+                size: 0,
+                mode: 0,
+                modified: None,
+            }),
+            Message::FileData {
+                data: Vec::new(),
+                size: 0,
+            },
+            Message::FsDone,
+            Message::FsError(fs::FsError {
+                kind: fs::FsErrorKind::Other,
+                message: String::new(),
+            }),
+            Message::Cancel { request_id: 0 },
+            Message::Signal {
+                request_id: 0,
+                signal: 0,
+            },
+            Message::PtyOpen(PtyOpen {
+                command: String::new(),
+                args: Vec::new(),
+                env: HashMap::new(),
+                size: TerminalSize { rows: 0, cols: 0 },
+            }),
+            Message::PtyInput {
+                request_id: 0,
+                data: Vec::new(),
+            },
+            Message::PtyResize {
+                request_id: 0,
+                size: TerminalSize { rows: 0, cols: 0 },
+            },
+            Message::StdinChunk {
+                request_id: 0,
+                data: Vec::new(),
+            },
+            Message::Error {
+                request_id: 0,
+                kind: ErrorKind::Internal,
+                message: String::new(),
+            },
+            Message::Ping { nonce: 0 },
+            Message::Pong { nonce: 0 },
+            Message::StdinClose { request_id: 0 },
+            Message::Shutdown,
+        ];
+
+        // Request ID, then the variant's position
+        for (index, message) in variants.into_iter().enumerate() {
+            let name = format!("{:?}", message);
+            assert_eq!(encode(message)[..2], [1, index as u8], "{}", name);
+        }
+
+        let workspace = Message::RunWorkspace(WorkspaceRunOptions {
+            source: WorkspaceSource::Inline(vec![0xAA]),
+            entrypoint: "a".to_string(),
+            stream_output: true,
+            timeout_ms: Some(5),
+            artifacts: vec!["b".to_string()],
+            compression: tar::Compression::Zstd,
+        });
+        assert_eq!(
+            encode(workspace),
+            [1, 2, 0, 1, 0xAA, 1, b'a', 1, 1, 5, 1, 1, b'b', 2]
+        );
+
+        let error = Message::Error {
+            request_id: 4,
+            kind: ErrorKind::Unsupported,
+            message: "c".to_string(),
+        };
+        assert_eq!(encode(error), [1, 30, 4, 4, 1, b'c']);
+    }
+
+    #[tokio::test]
+    async fn test_recv_rejects_unknown_version() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let body = b"{}";
+        client
+            .write_all(&(body.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        client.write_all(&99u32.to_be_bytes()).await.unwrap();
+        client.write_all(body).await.unwrap();
+
+        let err = recv_msg(&mut server).await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            ProtocolError::UnsupportedVersion {
+                version: 99,
+                max: MAX_VERSION,
+                ..
+            }
+        ));
+
+        // The rejected body was consumed, the next frame still decodes
+        send_msg(&mut client, 5, Message::Shutdown).await.unwrap();
+
+        assert_eq!(recv_msg(&mut server).await.unwrap().request_id, 5);
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_json_only_peer_gets_no_binary_frames() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+
+        let options = WireOptions {
+            max_version: JSON_VERSION,
+            ..Default::default()
+        };
+
+        let msg = Message::StdinChunk {
+            request_id: 1,
+            data: vec![1, 2, 3],
+        };
+
+        send_msg_with(&mut client, 2, msg, &options).await.unwrap();
+
+        let envelope = recv_msg_with(&mut server, &options).await.unwrap();
+
+        assert_eq!(envelope.version, JSON_VERSION);
+    }
+
+    #[test]
+    fn test_hello_negotiation() {
+        let ours = Hello::new(Vec::new());
+
+        let older = Hello {
+            min_version: JSON_VERSION,
+            max_version: JSON_VERSION,
+            capabilities: Vec::new(),
+        };
+        assert_eq!(ours.negotiate(&older).unwrap(), JSON_VERSION);
+
+        assert_eq!(ours.negotiate(&ours).unwrap(), BINARY_VERSION);
+
+        // Binary layouts of different versions are not compatible
+        let older_binary = Hello {
+            min_version: MIN_VERSION,
+            max_version: BINARY_VERSION - 1,
+            capabilities: Vec::new(),
+        };
+        assert_eq!(ours.negotiate(&older_binary).unwrap(), JSON_VERSION);
+
+        let newer = Hello {
+            min_version: MIN_VERSION,
+            max_version: MAX_VERSION + 5,
+            capabilities: Vec::new(),
+        };
+        assert_eq!(ours.negotiate(&newer).unwrap(), JSON_VERSION);
+
+        let too_new = Hello {
+            min_version: MAX_VERSION + 1,
+            max_version: MAX_VERSION + 5,
+            capabilities: Vec::new(),
+        };
+        let err = ours.negotiate(&too_new).unwrap_err();
+        assert!(matches!(
+            err,
+            ProtocolError::UnsupportedVersion { version, .. } if version == MAX_VERSION + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_recv_reports_disconnect_and_malformed_frames() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let body = b"not json";
+        client
+            .write_all(&(body.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        client.write_all(&JSON_VERSION.to_be_bytes()).await.unwrap();
+        client.write_all(body).await.unwrap();
+
+        let err = recv_msg(&mut server).await.unwrap_err();
+        assert!(matches!(err, ProtocolError::Malformed(_)));
+
+        // Closing in the middle of a frame
+        client.write_all(&8u32.to_be_bytes()).await.unwrap();
+        drop(client);
+
+        let err = recv_msg(&mut server).await.unwrap_err();
+        assert!(matches!(err, ProtocolError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_error_round_trip() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let error = Message::Error {
+            request_id: 7,
+            kind: ErrorKind::EntrypointNotFound,
+            message: "run.sh not found in workspace".to_string(),
+        };
+
+        assert!(error.is_final());
+
+        send_msg(&mut client, 7, error).await.unwrap();
+
+        match recv_msg(&mut server).await.unwrap().message {
+            Message::Error { kind, message, .. } => {
+                assert_eq!(kind, ErrorKind::EntrypointNotFound);
+                assert_eq!(message, "run.sh not found in workspace");
+            }
+            m => panic!("unexpected message: {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_protocol_error_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
 
-    Ok(envelope.message)
+        assert_send_sync::<ProtocolError>();
+    }
 }
@@ -1,49 +1,328 @@
-use std::process::Command;
-use tracing::error;
-
-pub fn tar_workspace(workspace_dir: &str, tar_path: &str) -> std::io::Result<()> {
-    let output = Command::new("tar")
-        .arg("-cf")
-        .arg(tar_path)
-        .arg("-C")
-        .arg(workspace_dir)
-        .arg(".")
-        .output()?;
-
-    if !output.status.success() {
-        error!(
-            "Error creating tarball: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-        return Err(std::io::Error::other("Failed to create tarball"));
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use ::tar::{Archive, Builder, EntryType};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+
+/// Compression applied on top of a tarball
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Bounds on what `unpack` extracts from an untrusted archive
+#[derive(Debug, Clone, Copy)]
+pub struct UnpackLimits {
+    /// Sum of the sizes of all entries
+    pub max_total_size: u64,
+    pub max_entries: usize,
+}
+
+impl Default for UnpackLimits {
+    fn default() -> Self {
+        UnpackLimits {
+            max_total_size: 4 * 1024 * 1024 * 1024,
+            max_entries: 100_000,
+        }
     }
+}
 
-    Ok(())
+pub fn tar_workspace(
+    workspace_dir: &str,
+    tar_path: &str,
+    compression: Compression,
+) -> io::Result<()> {
+    let file = File::create(tar_path)?;
+
+    pack_dir(Path::new(workspace_dir), file, compression)?.sync_all()
 }
 
-pub fn untar_workspace(tar_path: &str, dest_dir: &str) -> std::io::Result<()> {
-    let output = Command::new("tar")
-        .arg("-xf")
-        .arg(tar_path)
-        .arg("-C")
-        .arg(dest_dir)
-        .output()?;
+/// Packs `files`, given relative to `base_dir`, into a tarball
+pub fn tar_files(base_dir: &str, files: &[String], tar_path: &str) -> io::Result<()> {
+    let file = File::create(tar_path)?;
 
-    if !output.status.success() {
-        error!(
-            "Error extracting tarball: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-        return Err(std::io::Error::other("Failed to extract tarball"));
+    pack_files(Path::new(base_dir), files, file)?.sync_all()
+}
+
+pub fn untar_workspace(tar_path: &str, dest_dir: &str, compression: Compression) -> io::Result<()> {
+    let file = File::open(tar_path)?;
+
+    unpack(
+        file,
+        Path::new(dest_dir),
+        &UnpackLimits::default(),
+        compression,
+    )
+}
+
+/// Writes a tarball of everything below `dir` to `writer`, compressing it as
+/// it is produced, and returns the writer. Symlinks are stored as links.
+pub fn pack_dir<W: Write>(dir: &Path, writer: W, compression: Compression) -> io::Result<W> {
+    compressed(writer, compression, |out| {
+        let mut builder = Builder::new(out);
+        builder.follow_symlinks(false);
+
+        builder.append_dir_all(".", dir)?;
+        builder.finish()
+    })
+}
+
+/// Runs `pack` against `writer` wrapped in the encoder for `compression`
+/// and finishes the encoder afterwards
+fn compressed<W: Write>(
+    writer: W,
+    compression: Compression,
+    pack: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<W> {
+    match compression {
+        Compression::None => {
+            let mut writer = writer;
+            pack(&mut writer)?;
+            Ok(writer)
+        }
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(writer, flate2::Compression::default());
+            pack(&mut encoder)?;
+            encoder.finish()
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            pack(&mut encoder)?;
+            encoder.finish()
+        }
+    }
+}
+
+fn decompressed<'a, R: Read + 'a>(
+    reader: R,
+    compression: Compression,
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(GzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+    })
+}
+
+/// Writes a tarball of `files`, given relative to `base_dir`, to `writer`
+/// and returns the writer
+pub fn pack_files<W: Write>(base_dir: &Path, files: &[String], writer: W) -> io::Result<W> {
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+
+    for file in files {
+        builder.append_path_with_name(base_dir.join(file), file)?;
+    }
+
+    builder.into_inner()
+}
+
+/// Extracts a tarball read from `reader` into `dest`, which must exist,
+/// decompressing it on the way.
+///
+/// Fails before writing an entry that would land outside `dest`: absolute
+/// paths, `..` components, paths through symlinks unpacked earlier and links
+/// whose target escapes `dest`. Symlink targets may only climb with leading
+/// `..`s, so that no link resolves through another one and then climbs out.
+/// Device files and other special entries are rejected as well. Extraction stops
+/// once `limits` are exceeded, leaving the entries written so far. The
+/// limits apply to the decompressed sizes.
+pub fn unpack<R: Read>(
+    reader: R,
+    dest: &Path,
+    limits: &UnpackLimits,
+    compression: Compression,
+) -> io::Result<()> {
+    let mut archive = Archive::new(decompressed(reader, compression)?);
+    archive.set_preserve_permissions(true);
+    archive.set_overwrite(true);
+
+    let mut total_size = 0u64;
+
+    for (count, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+
+        if count >= limits.max_entries {
+            return Err(invalid(format!(
+                "Archive has more than {} entries",
+                limits.max_entries
+            )));
+        }
+
+        total_size = total_size.saturating_add(entry.header().size()?);
+
+        if total_size > limits.max_total_size {
+            return Err(invalid(format!(
+                "Archive is larger than {} bytes",
+                limits.max_total_size
+            )));
+        }
+
+        let path = entry.path()?.into_owned();
+        let relative = confine(&path)
+            .ok_or_else(|| invalid(format!("Entry {:?} escapes the destination", path)))?;
+
+        if crosses_symlink(dest, &relative) {
+            return Err(invalid(format!("Entry {:?} goes through a symlink", path)));
+        }
+
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Directory => (),
+            EntryType::Symlink | EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| invalid(format!("Link {:?} has no target", path)))?;
+
+                // Hard link targets are relative to the archive root, symlink
+                // targets to the directory holding the link
+                let (resolved, escapes) = match entry.header().entry_type() {
+                    EntryType::Link => {
+                        let escapes = confine(&target)
+                            .is_none_or(|target| crosses_symlink(dest, &target))
+                            || target.components().any(|c| c == Component::ParentDir);
+
+                        (target.to_path_buf(), escapes)
+                    }
+                    _ => (
+                        relative.parent().unwrap_or(Path::new("")).join(&target),
+                        climbs_after_descending(&target),
+                    ),
+                };
+
+                if target.is_absolute() || escapes || confine(&resolved).is_none() {
+                    return Err(invalid(format!(
+                        "Link {:?} points outside the destination: {:?}",
+                        path, target
+                    )));
+                }
+            }
+            // Metadata entries carry no file of their own
+            EntryType::XGlobalHeader | EntryType::XHeader => continue,
+            other => {
+                return Err(invalid(format!(
+                    "Entry {:?} has unsupported type {:?}",
+                    path, other
+                )));
+            }
+        }
+
+        entry.unpack_in(dest)?;
     }
 
     Ok(())
 }
 
+/// Normalizes a path from an archive, or returns `None` if it is absolute
+/// or climbs above where it starts
+fn confine(path: &Path) -> Option<PathBuf> {
+    let mut confined = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => confined.push(part),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !confined.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(confined)
+}
+
+/// Whether a directory on the way from `dest` to `relative`, a confined
+/// path, is a symlink already on disk
+fn crosses_symlink(dest: &Path, relative: &Path) -> bool {
+    let mut path = dest.to_path_buf();
+    let mut components = relative.components().peekable();
+
+    while let Some(component) = components.next() {
+        // The entry itself may replace a symlink, only its parents matter
+        if components.peek().is_none() {
+            break;
+        }
+
+        path.push(component);
+
+        if path
+            .symlink_metadata()
+            .is_ok_and(|meta| meta.file_type().is_symlink())
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Whether a symlink target has a `..` after a normal component. Such a `..`
+/// climbs from wherever the component resolves to, which may be another
+/// symlink's target rather than the directory it seems to be.
+fn climbs_after_descending(target: &Path) -> bool {
+    let mut descended = false;
+
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => descended = true,
+            Component::ParentDir if descended => return true,
+            _ => (),
+        }
+    }
+
+    false
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Appends an entry with its name written verbatim, bypassing the
+    /// builder's own path checks
+    fn append_raw(
+        builder: &mut Builder<Vec<u8>>,
+        name: &str,
+        entry_type: EntryType,
+        link: Option<&str>,
+        data: &[u8],
+    ) {
+        let mut header = ::tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+
+        if let Some(link) = link {
+            header.as_gnu_mut().unwrap().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        }
+
+        header.set_entry_type(entry_type);
+        header.set_mode(match entry_type {
+            EntryType::Directory => 0o755,
+            _ => 0o644,
+        });
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+
+        builder.append(&header, data).unwrap();
+    }
+
+    fn archive_with(name: &str, entry_type: EntryType, link: Option<&str>) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        append_raw(&mut builder, name, entry_type, link, b"data");
+        builder.into_inner().unwrap()
+    }
+
     #[test]
     fn test_tar_workspace() {
         let temp_dir = tempfile::tempdir().unwrap();
@@ -55,7 +334,12 @@ mod tests {
         let test_file = workspace_dir.join("test.txt");
         std::fs::write(&test_file, "test content").unwrap();
 
-        tar_workspace(workspace_dir.to_str().unwrap(), tar_path.to_str().unwrap()).unwrap();
+        tar_workspace(
+            workspace_dir.to_str().unwrap(),
+            tar_path.to_str().unwrap(),
+            Compression::None,
+        )
+        .unwrap();
 
         assert!(tar_path.exists());
     }
@@ -71,13 +355,246 @@ mod tests {
         let test_file = workspace_dir.join("test.txt");
         std::fs::write(&test_file, "test content").unwrap();
 
-        tar_workspace(workspace_dir.to_str().unwrap(), tar_path.to_str().unwrap()).unwrap();
+        tar_workspace(
+            workspace_dir.to_str().unwrap(),
+            tar_path.to_str().unwrap(),
+            Compression::None,
+        )
+        .unwrap();
         std::fs::create_dir_all(&extract_dir).unwrap();
-        untar_workspace(tar_path.to_str().unwrap(), extract_dir.to_str().unwrap()).unwrap();
+        untar_workspace(
+            tar_path.to_str().unwrap(),
+            extract_dir.to_str().unwrap(),
+            Compression::None,
+        )
+        .unwrap();
 
         let extracted_file = extract_dir.join("test.txt");
         assert!(extracted_file.exists());
         let content = std::fs::read_to_string(extracted_file).unwrap();
         assert_eq!(content, "test content");
     }
+
+    #[test]
+    fn test_tar_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let base_dir = temp_dir.path().join("base");
+        let tar_path = temp_dir.path().join("files.tar");
+        let extract_dir = temp_dir.path().join("extracted");
+
+        std::fs::create_dir_all(base_dir.join("out")).unwrap();
+        std::fs::create_dir_all(&extract_dir).unwrap();
+        std::fs::write(base_dir.join("out/result.txt"), "result").unwrap();
+        std::fs::write(base_dir.join("skipped.txt"), "skipped").unwrap();
+
+        let files = vec!["out/result.txt".to_string()];
+
+        tar_files(
+            base_dir.to_str().unwrap(),
+            &files,
+            tar_path.to_str().unwrap(),
+        )
+        .unwrap();
+        untar_workspace(
+            tar_path.to_str().unwrap(),
+            extract_dir.to_str().unwrap(),
+            Compression::None,
+        )
+        .unwrap();
+
+        assert!(extract_dir.join("out/result.txt").exists());
+        assert!(!extract_dir.join("skipped.txt").exists());
+    }
+
+    #[test]
+    fn test_unpack_rejects_escaping_entries() {
+        let cases = [
+            archive_with("../evil.txt", EntryType::Regular, None),
+            archive_with("a/../../evil.txt", EntryType::Regular, None),
+            archive_with("/tmp/evil.txt", EntryType::Regular, None),
+            archive_with("link", EntryType::Symlink, Some("/etc")),
+            archive_with("a/link", EntryType::Symlink, Some("../../etc")),
+            archive_with("hard", EntryType::Link, Some("../etc/passwd")),
+            archive_with("dev", EntryType::Char, None),
+        ];
+
+        for archive in cases {
+            let temp_dir = tempfile::tempdir().unwrap();
+            let dest = temp_dir.path().join("dest");
+            std::fs::create_dir_all(&dest).unwrap();
+
+            let err = unpack(
+                &archive[..],
+                &dest,
+                &UnpackLimits::default(),
+                Compression::None,
+            )
+            .unwrap_err();
+
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(!temp_dir.path().join("evil.txt").exists());
+            assert_eq!(std::fs::read_dir(&dest).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_unpack_rejects_chained_links() {
+        // `d1/l` is `dest` itself, so `d1/l/l2 -> ..` would be `dest/..`
+        let mut through_link = Builder::new(Vec::new());
+        append_raw(&mut through_link, "d1/", EntryType::Directory, None, b"");
+        append_raw(
+            &mut through_link,
+            "d1/l",
+            EntryType::Symlink,
+            Some(".."),
+            b"",
+        );
+        append_raw(
+            &mut through_link,
+            "d1/l/l2",
+            EntryType::Symlink,
+            Some(".."),
+            b"",
+        );
+
+        // `a/..` climbs from wherever `a` resolves to, here `dest/..`
+        let mut climbing_link = Builder::new(Vec::new());
+        append_raw(
+            &mut climbing_link,
+            "x",
+            EntryType::Symlink,
+            Some("a/.."),
+            b"",
+        );
+        append_raw(&mut climbing_link, "a", EntryType::Symlink, Some("."), b"");
+
+        for builder in [through_link, climbing_link] {
+            let archive = builder.into_inner().unwrap();
+
+            let temp_dir = tempfile::tempdir().unwrap();
+            let dest = temp_dir.path().join("dest");
+            std::fs::create_dir_all(&dest).unwrap();
+
+            let err = unpack(
+                &archive[..],
+                &dest,
+                &UnpackLimits::default(),
+                Compression::None,
+            )
+            .unwrap_err();
+
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(dest.join("l2").symlink_metadata().is_err());
+            assert!(dest.join("x").symlink_metadata().is_err());
+        }
+    }
+
+    #[test]
+    fn test_unpack_allows_links_inside_destination() {
+        let mut builder = Builder::new(Vec::new());
+        append_raw(
+            &mut builder,
+            "dir/file.txt",
+            EntryType::Regular,
+            None,
+            b"data",
+        );
+        append_raw(
+            &mut builder,
+            "dir/link",
+            EntryType::Symlink,
+            Some("../dir/file.txt"),
+            b"",
+        );
+        let archive = builder.into_inner().unwrap();
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        unpack(
+            &archive[..],
+            temp_dir.path(),
+            &UnpackLimits::default(),
+            Compression::None,
+        )
+        .unwrap();
+
+        let content = std::fs::read_to_string(temp_dir.path().join("dir/link")).unwrap();
+        assert_eq!(content, "data");
+    }
+
+    #[test]
+    fn test_unpack_enforces_limits() {
+        let mut builder = Builder::new(Vec::new());
+        append_raw(
+            &mut builder,
+            "one.txt",
+            EntryType::Regular,
+            None,
+            &[0u8; 600],
+        );
+        append_raw(
+            &mut builder,
+            "two.txt",
+            EntryType::Regular,
+            None,
+            &[0u8; 600],
+        );
+        let archive = builder.into_inner().unwrap();
+
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let too_many = UnpackLimits {
+            max_total_size: u64::MAX,
+            max_entries: 1,
+        };
+        let err = unpack(&archive[..], temp_dir.path(), &too_many, Compression::None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let too_large = UnpackLimits {
+            max_total_size: 1000,
+            max_entries: 10,
+        };
+        let err = unpack(&archive[..], temp_dir.path(), &too_large, Compression::None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let workspace_dir = temp_dir.path().join("workspace");
+
+        std::fs::create_dir_all(workspace_dir.join("src")).unwrap();
+        std::fs::write(
+            workspace_dir.join("src/main.py"),
+            "print('hi')\n".repeat(1000),
+        )
+        .unwrap();
+
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let data = pack_dir(&workspace_dir, Vec::new(), compression).unwrap();
+
+            if compression != Compression::None {
+                assert!(
+                    data.len() < 2000,
+                    "{:?} left {} bytes",
+                    compression,
+                    data.len()
+                );
+            }
+
+            let extract_dir = temp_dir.path().join(format!("{:?}", compression));
+            std::fs::create_dir_all(&extract_dir).unwrap();
+
+            unpack(
+                &data[..],
+                &extract_dir,
+                &UnpackLimits::default(),
+                compression,
+            )
+            .unwrap();
+
+            let content = std::fs::read_to_string(extract_dir.join("src/main.py")).unwrap();
+            assert_eq!(content, "print('hi')\n".repeat(1000));
+        }
+    }
 }
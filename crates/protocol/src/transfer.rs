@@ -0,0 +1,50 @@
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Size of every `UploadChunk` but the last one
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Hex encoded SHA-256 of a file, read in small pieces so large files are
+/// never held in memory
+pub async fn sha256_file(path: impl AsRef<Path>) -> std::io::Result<String> {
+    let file = tokio::fs::File::open(path).await?;
+
+    sha256_reader(file).await
+}
+
+/// Hex encoded SHA-256 of everything `reader` yields until its end
+pub async fn sha256_reader(mut reader: impl AsyncRead + Unpin) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+
+    loop {
+        match reader.read(&mut buf).await? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sha256_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.txt");
+
+        std::fs::write(&path, b"abc").unwrap();
+
+        assert_eq!(
+            sha256_file(&path).await.unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -4,6 +4,6 @@ echo "Running guest bash entrypoint script at $(date)"
 
 echo "Executing Python script in guest"
 
-python3 test.py
+python3 test.py | tee output.txt
 
 echo "Python script executed"